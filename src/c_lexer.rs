@@ -0,0 +1,226 @@
+use std::path::Path;
+use std::sync::Arc;
+
+/// A preprocessing token of C source, good enough for keymap.c files
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Ident(String),
+    Number(String),
+    Str(String),
+    Char(String),
+    Punct(&'static str),
+}
+
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub line: usize,
+    pub col: usize,
+    /// First token on its line, which is what makes `#` start a directive
+    pub line_start: bool,
+    /// Outermost macro the token was expanded from, e.g. `_NAV` for a `#define _NAV 2`
+    pub origin: Option<String>,
+    /// The file the token is in, set by the preprocessor
+    pub file: Option<Arc<Path>>,
+}
+
+impl Token {
+    pub fn ident(&self) -> Option<&str> {
+        match &self.kind {
+            TokenKind::Ident(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    pub fn is_punct(&self, punct: &str) -> bool {
+        matches!(self.kind, TokenKind::Punct(p) if p == punct)
+    }
+
+    pub fn text(&self) -> String {
+        match &self.kind {
+            TokenKind::Ident(s) | TokenKind::Number(s) => s.clone(),
+            TokenKind::Str(s) => format!("\"{}\"", s),
+            TokenKind::Char(s) => format!("'{}'", s),
+            TokenKind::Punct(p) => p.to_string(),
+        }
+    }
+}
+
+// longest first so `<<=` wins over `<<` and `<`
+const PUNCTUATORS: &[&str] = &[
+    "<<=", ">>=", "...", "->", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=",
+    "-=", "*=", "/=", "%=", "&=", "|=", "^=", "##", "[", "]", "(", ")", "{", "}", ".", "&", "*",
+    "+", "-", "~", "!", "/", "%", "<", ">", "^", "|", "?", ":", ";", "=", ",", "#",
+];
+
+pub fn tokenize(source: &str) -> Vec<Token> {
+    let chars = source.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    let mut line = 1;
+    let mut line_begin = 0;
+    let mut line_start = true;
+
+    // a backslash-newline joins lines without starting a new one, so directives keep going
+    let skip_continuation = |i: &mut usize, line: &mut usize, line_begin: &mut usize| {
+        while chars.get(*i) == Some(&'\\') {
+            let next = match chars.get(*i + 1) {
+                Some('\n') => 1,
+                Some('\r') if chars.get(*i + 2) == Some(&'\n') => 2,
+                _ => break,
+            };
+            *i += next + 1;
+            *line += 1;
+            *line_begin = *i;
+        }
+    };
+
+    while i < chars.len() {
+        skip_continuation(&mut i, &mut line, &mut line_begin);
+        let Some(&c) = chars.get(i) else {
+            break;
+        };
+
+        if c == '\n' {
+            i += 1;
+            line += 1;
+            line_begin = i;
+            line_start = true;
+            continue;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == '/' && chars.get(i + 1) == Some(&'/') {
+            // a trailing backslash continues a line comment too
+            while i < chars.len() && chars[i] != '\n' {
+                if chars[i] == '\\' && chars.get(i + 1) == Some(&'\n') {
+                    i += 1;
+                    line += 1;
+                    line_begin = i + 1;
+                }
+                i += 1;
+            }
+            continue;
+        }
+        if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                if chars[i] == '\n' {
+                    line += 1;
+                    line_begin = i + 1;
+                }
+                i += 1;
+            }
+            i += 2;
+            continue;
+        }
+
+        let start = i;
+        let col = start - line_begin + 1;
+        let kind = if c.is_ascii_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            TokenKind::Ident(chars[start..i].iter().collect())
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()))
+        {
+            // pp-number: digits, letters, dots and signed exponents
+            i += 1;
+            while i < chars.len() {
+                let ch = chars[i];
+                let exponent_sign =
+                    matches!(ch, '+' | '-') && matches!(chars[i - 1], 'e' | 'E' | 'p' | 'P');
+                if !(exponent_sign || ch.is_ascii_alphanumeric() || ch == '_' || ch == '.') {
+                    break;
+                }
+                i += 1;
+            }
+            TokenKind::Number(chars[start..i].iter().collect())
+        } else if c == '"' || c == '\'' {
+            i += 1;
+            let mut text = String::new();
+            while i < chars.len() && chars[i] != c && chars[i] != '\n' {
+                if chars[i] == '\\' && i + 1 < chars.len() {
+                    text.push(chars[i]);
+                    i += 1;
+                }
+                text.push(chars[i]);
+                i += 1;
+            }
+            i += 1;
+            if c == '"' {
+                TokenKind::Str(text)
+            } else {
+                TokenKind::Char(text)
+            }
+        } else if let Some(punct) = PUNCTUATORS.iter().find(|p| {
+            p.chars()
+                .enumerate()
+                .all(|(n, pc)| chars.get(i + n) == Some(&pc))
+        }) {
+            i += punct.len();
+            TokenKind::Punct(punct)
+        } else {
+            // stray characters like `@` or `$` don't matter for keymaps
+            i += 1;
+            continue;
+        };
+
+        tokens.push(Token {
+            kind,
+            line,
+            col,
+            line_start,
+            origin: None,
+            file: None,
+        });
+        line_start = false;
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(source: &str) -> Vec<String> {
+        tokenize(source).iter().map(Token::text).collect()
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(texts("a // b\nc /* d\ne */ f"), ["a", "c", "f"]);
+        // a line comment continued onto the next line
+        assert_eq!(texts("a // b \\\nc\nd"), ["a", "d"]);
+        let tokens = tokenize("a /* x\ny */ b\nc");
+        assert_eq!((tokens[1].line, tokens[1].col, tokens[1].line_start), (2, 6, false));
+        assert_eq!((tokens[2].line, tokens[2].line_start), (3, true));
+    }
+
+    #[test]
+    fn continuations() {
+        let tokens = tokenize("#define A \\\n  1\nB");
+        assert_eq!(
+            tokens.iter().map(|t| (t.text(), t.line, t.line_start)).collect::<Vec<_>>(),
+            [
+                ("#".to_string(), 1, true),
+                ("define".to_string(), 1, false),
+                ("A".to_string(), 1, false),
+                ("1".to_string(), 2, false),
+                ("B".to_string(), 3, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            texts(r#"x <<= 0x1Fu 1e+5 'a' "s\"t" ... ## ->"#),
+            ["x", "<<=", "0x1Fu", "1e+5", "'a'", r#""s\"t""#, "...", "##", "->"]
+        );
+        assert!(matches!(tokenize("LT(")[1].kind, TokenKind::Punct("(")));
+    }
+}
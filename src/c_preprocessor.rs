@@ -0,0 +1,655 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::c_lexer::{Token, TokenKind, tokenize};
+
+#[derive(Debug, Clone)]
+pub enum Macro {
+    Object(Vec<Token>),
+    Function {
+        params: Vec<String>,
+        variadic: bool,
+        body: Vec<Token>,
+    },
+}
+
+/// A small C preprocessor for keymaps: local `#include`s, object and function-like
+/// `#define`s and conditionals. System headers like `QMK_KEYBOARD_H` aren't available, so
+/// includes that can't be found are skipped and QMK's own keycode macros stay unexpanded.
+pub struct Preprocessor {
+    pub defines: HashMap<String, Macro>,
+    include_dirs: Vec<PathBuf>,
+    include_depth: usize,
+}
+
+/// A token together with the macros it came out of, those can't expand again
+#[derive(Clone)]
+struct HidTok {
+    token: Token,
+    hide: Vec<String>,
+}
+
+struct Conditional {
+    /// Whether the enclosing block is being kept
+    parent_active: bool,
+    active: bool,
+    /// A branch of this `#if` chain was already taken
+    taken: bool,
+}
+
+impl Preprocessor {
+    pub fn new(include_dirs: Vec<PathBuf>) -> Self {
+        Preprocessor {
+            defines: HashMap::new(),
+            include_dirs,
+            include_depth: 0,
+        }
+    }
+
+    /// Runs a header only for its `#define`s, e.g. config.h
+    pub fn load_defines(&mut self, path: &Path) {
+        if path.is_file() {
+            let _ = self.process_file(path);
+        }
+    }
+
+    pub fn process_file(&mut self, path: &Path) -> Result<Vec<Token>, String> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        Ok(self.process_source(&source, path))
+    }
+
+    /// Preprocesses the text of the file at `path`, which is where its includes are looked up
+    pub fn process_source(&mut self, source: &str, path: &Path) -> Vec<Token> {
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let file: Arc<Path> = Arc::from(path);
+        let mut tokens = tokenize(source);
+        for token in &mut tokens {
+            token.file = Some(file.clone());
+        }
+        self.process(&tokens, &dir)
+    }
+
+    fn process(&mut self, tokens: &[Token], dir: &Path) -> Vec<Token> {
+        let mut out = Vec::new();
+        let mut text = Vec::new();
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        let mut i = 0;
+
+        while i < tokens.len() {
+            let line_end = tokens[i + 1..]
+                .iter()
+                .position(|t| t.line_start)
+                .map_or(tokens.len(), |p| i + 1 + p);
+
+            if !(tokens[i].line_start && tokens[i].is_punct("#")) {
+                if conditionals.last().is_none_or(|c| c.active) {
+                    text.extend_from_slice(&tokens[i..line_end]);
+                }
+                i = line_end;
+                continue;
+            }
+
+            let directive = &tokens[i + 1..line_end];
+            i = line_end;
+            let Some(name) = directive.first().and_then(Token::ident) else {
+                continue; // null directive
+            };
+            let args = &directive[1..];
+            let active = conditionals.last().is_none_or(|c| c.active);
+
+            match name {
+                "if" | "ifdef" | "ifndef" => {
+                    let cond = active
+                        && match name {
+                            "ifdef" => self.is_defined(args),
+                            "ifndef" => !self.is_defined(args),
+                            _ => self.eval_condition(args),
+                        };
+                    conditionals.push(Conditional {
+                        parent_active: active,
+                        active: cond,
+                        taken: cond,
+                    });
+                }
+                "elif" | "elifdef" | "elifndef" => {
+                    let Some(last) = conditionals.last() else {
+                        continue;
+                    };
+                    let try_branch = last.parent_active && !last.taken;
+                    let cond = try_branch
+                        && match name {
+                            "elifdef" => self.is_defined(args),
+                            "elifndef" => !self.is_defined(args),
+                            _ => self.eval_condition(args),
+                        };
+                    let last = conditionals.last_mut().unwrap();
+                    last.active = cond;
+                    last.taken |= cond;
+                }
+                "else" => {
+                    if let Some(last) = conditionals.last_mut() {
+                        last.active = last.parent_active && !last.taken;
+                        last.taken = true;
+                    }
+                }
+                "endif" => {
+                    conditionals.pop();
+                }
+                _ if !active => {}
+                // text before these has to see the defines from before them
+                "define" => {
+                    out.extend(self.expand(std::mem::take(&mut text)));
+                    self.define(args);
+                }
+                "undef" => {
+                    out.extend(self.expand(std::mem::take(&mut text)));
+                    if let Some(name) = args.first().and_then(Token::ident) {
+                        self.defines.remove(name);
+                    }
+                }
+                "include" => {
+                    out.extend(self.expand(std::mem::take(&mut text)));
+                    out.extend(self.include(args, dir));
+                }
+                // #pragma, #error, #warning, #line don't change what the keymap contains
+                _ => {}
+            }
+        }
+
+        out.extend(self.expand(text));
+        out
+    }
+
+    fn define(&mut self, args: &[Token]) {
+        let Some(name) = args.first().and_then(Token::ident) else {
+            return;
+        };
+        // function-like only when the `(` directly follows the name
+        let is_function = args.get(1).is_some_and(|t| {
+            t.is_punct("(") && t.line == args[0].line && t.col == args[0].col + name.len()
+        });
+        if !is_function {
+            self.defines
+                .insert(name.to_string(), Macro::Object(args[1..].to_vec()));
+            return;
+        }
+
+        let mut params = Vec::new();
+        let mut variadic = false;
+        let mut i = 2;
+        while let Some(token) = args.get(i) {
+            i += 1;
+            if token.is_punct(")") {
+                break;
+            } else if token.is_punct("...") {
+                variadic = true;
+            } else if let Some(param) = token.ident() {
+                params.push(param.to_string());
+            }
+        }
+        self.defines.insert(
+            name.to_string(),
+            Macro::Function {
+                params,
+                variadic,
+                body: args[i.min(args.len())..].to_vec(),
+            },
+        );
+    }
+
+    fn include(&mut self, args: &[Token], dir: &Path) -> Vec<Token> {
+        let name = match args.first().map(|t| &t.kind) {
+            Some(TokenKind::Str(name)) => name.clone(),
+            // `#include <...>` and `#include QMK_KEYBOARD_H` point into the firmware
+            _ => return Vec::new(),
+        };
+        let Some(path) = std::iter::once(dir)
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .map(|d| d.join(&name))
+            .find(|p| p.is_file())
+        else {
+            return Vec::new();
+        };
+        // guard against headers without include guards including each other
+        if self.include_depth > 32 {
+            return Vec::new();
+        }
+        self.include_depth += 1;
+        // a header that can't be read is skipped like one that can't be found
+        let tokens = self.process_file(&path).unwrap_or_default();
+        self.include_depth -= 1;
+        tokens
+    }
+
+    fn is_defined(&self, args: &[Token]) -> bool {
+        args.first()
+            .and_then(Token::ident)
+            .is_some_and(|name| self.defines.contains_key(name))
+    }
+
+    fn eval_condition(&self, args: &[Token]) -> bool {
+        // `defined` has to be resolved before its operand gets expanded
+        let mut resolved = Vec::new();
+        let mut i = 0;
+        while i < args.len() {
+            if args[i].ident() == Some("defined") {
+                let (name, skip) = if args.get(i + 1).is_some_and(|t| t.is_punct("(")) {
+                    (args.get(i + 2), 4)
+                } else {
+                    (args.get(i + 1), 2)
+                };
+                let defined = name
+                    .and_then(Token::ident)
+                    .is_some_and(|n| self.defines.contains_key(n));
+                resolved.push(Token {
+                    kind: TokenKind::Number(if defined { "1" } else { "0" }.to_string()),
+                    ..args[i].clone()
+                });
+                i += skip;
+            } else {
+                resolved.push(args[i].clone());
+                i += 1;
+            }
+        }
+        let expanded = self.expand(resolved);
+        let mut parser = ExprParser {
+            tokens: &expanded,
+            pos: 0,
+        };
+        parser.ternary() != 0
+    }
+
+    /// Macro-expands a run of ordinary tokens
+    pub fn expand(&self, tokens: Vec<Token>) -> Vec<Token> {
+        let mut input = tokens
+            .into_iter()
+            .rev()
+            .map(|token| HidTok {
+                token,
+                hide: Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        let mut out = Vec::new();
+
+        // `input` is a stack with the next token on top, expansions get pushed back for rescanning
+        while let Some(tok) = input.pop() {
+            let macro_def = tok
+                .token
+                .ident()
+                .filter(|name| !tok.hide.iter().any(|h| h == name))
+                .and_then(|name| self.defines.get(name));
+            let Some(macro_def) = macro_def else {
+                out.push(tok.token);
+                continue;
+            };
+            let name = tok.token.ident().unwrap().to_string();
+            let mut hide = tok.hide.clone();
+            hide.push(name);
+
+            match macro_def {
+                Macro::Object(body) => {
+                    let replacement = self.substitute(body, &[], false, &tok.token);
+                    push_expansion(&mut input, replacement, &hide);
+                }
+                Macro::Function {
+                    params,
+                    variadic,
+                    body,
+                } => {
+                    if !input.last().is_some_and(|t| t.token.is_punct("(")) {
+                        out.push(tok.token);
+                        continue;
+                    }
+                    input.pop();
+                    let args = collect_args(&mut input);
+                    let mut bound = Vec::new();
+                    for (n, param) in params.iter().enumerate() {
+                        bound.push((param.clone(), args.get(n).cloned().unwrap_or_default()));
+                    }
+                    if *variadic {
+                        // re-join everything past the named parameters with commas
+                        let mut rest = Vec::new();
+                        for (n, arg) in args.iter().enumerate().skip(params.len()) {
+                            if n > params.len() {
+                                rest.push(Token {
+                                    kind: TokenKind::Punct(","),
+                                    ..tok.token.clone()
+                                });
+                            }
+                            rest.extend(arg.iter().cloned());
+                        }
+                        bound.push(("__VA_ARGS__".to_string(), rest));
+                    }
+                    let replacement = self.substitute(body, &bound, true, &tok.token);
+                    push_expansion(&mut input, replacement, &hide);
+                }
+            }
+        }
+        out
+    }
+
+    /// Replaces parameters in a macro body, handling `#` and `##`. Expanded tokens take the
+    /// position of the macro invocation.
+    fn substitute(
+        &self,
+        body: &[Token],
+        bound: &[(String, Vec<Token>)],
+        function_like: bool,
+        site: &Token,
+    ) -> Vec<Token> {
+        let arg = |token: &Token| {
+            token
+                .ident()
+                .and_then(|name| bound.iter().find(|(p, _)| p == name))
+                .map(|(_, tokens)| tokens)
+        };
+        let origin = site.origin.clone().or_else(|| site.ident().map(str::to_string));
+        let at_site = |kind: TokenKind| Token {
+            kind,
+            origin: origin.clone(),
+            ..site.clone()
+        };
+
+        let mut out: Vec<Token> = Vec::new();
+        let mut i = 0;
+        while i < body.len() {
+            let token = &body[i];
+            if function_like
+                && token.is_punct("#")
+                && let Some(tokens) = body.get(i + 1).and_then(arg)
+            {
+                let text = tokens.iter().map(Token::text).collect::<Vec<_>>().join(" ");
+                out.push(at_site(TokenKind::Str(text)));
+                i += 2;
+                continue;
+            }
+            if token.is_punct("##") {
+                let rhs = match body.get(i + 1) {
+                    Some(next) => match arg(next) {
+                        Some(tokens) => tokens.clone(),
+                        None => vec![next.clone()],
+                    },
+                    None => Vec::new(),
+                };
+                i += 2;
+                let mut rhs = rhs.into_iter();
+                match (out.pop(), rhs.next()) {
+                    (Some(lhs), Some(first)) => {
+                        let pasted = tokenize(&(lhs.text() + &first.text()));
+                        out.extend(pasted.into_iter().map(|t| at_site(t.kind)));
+                    }
+                    (lhs, first) => out.extend(lhs.into_iter().chain(first)),
+                }
+                out.extend(rhs);
+                continue;
+            }
+            let next_is_paste = body.get(i + 1).is_some_and(|t| t.is_punct("##"));
+            match arg(token) {
+                // operands of `##` are pasted unexpanded
+                Some(tokens) if next_is_paste => out.extend(tokens.iter().cloned()),
+                Some(tokens) => out.extend(self.expand(tokens.clone())),
+                None => out.push(at_site(token.kind.clone())),
+            }
+            i += 1;
+        }
+        out
+    }
+}
+
+fn push_expansion(input: &mut Vec<HidTok>, replacement: Vec<Token>, hide: &[String]) {
+    input.extend(replacement.into_iter().rev().map(|token| HidTok {
+        token,
+        hide: hide.to_vec(),
+    }));
+}
+
+/// Pops the arguments of a macro call after its `(`, splitting on top-level commas
+fn collect_args(input: &mut Vec<HidTok>) -> Vec<Vec<Token>> {
+    let mut args = vec![Vec::new()];
+    let mut depth = 0;
+    while let Some(tok) = input.pop() {
+        if tok.token.is_punct("(") {
+            depth += 1;
+        } else if tok.token.is_punct(")") {
+            if depth == 0 {
+                break;
+            }
+            depth -= 1;
+        } else if tok.token.is_punct(",") && depth == 0 {
+            args.push(Vec::new());
+            continue;
+        }
+        args.last_mut().unwrap().push(tok.token);
+    }
+    if args.len() == 1 && args[0].is_empty() {
+        args.clear();
+    }
+    args
+}
+
+/// Evaluates `#if` expressions, identifiers left after expansion count as 0
+struct ExprParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl ExprParser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if self.peek().is_some_and(|t| t.is_punct(punct)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn ternary(&mut self) -> i64 {
+        let cond = self.binary(0);
+        if self.eat("?") {
+            let a = self.ternary();
+            self.eat(":");
+            let b = self.ternary();
+            if cond != 0 { a } else { b }
+        } else {
+            cond
+        }
+    }
+
+    fn binary(&mut self, min_prec: u8) -> i64 {
+        const OPS: &[(&str, u8)] = &[
+            ("||", 1),
+            ("&&", 2),
+            ("|", 3),
+            ("^", 4),
+            ("&", 5),
+            ("==", 6),
+            ("!=", 6),
+            ("<", 7),
+            (">", 7),
+            ("<=", 7),
+            (">=", 7),
+            ("<<", 8),
+            (">>", 8),
+            ("+", 9),
+            ("-", 9),
+            ("*", 10),
+            ("/", 10),
+            ("%", 10),
+        ];
+        let mut lhs = self.unary();
+        loop {
+            let Some((op, prec)) = self.peek().and_then(|t| {
+                OPS.iter()
+                    .find(|(op, prec)| *prec > min_prec && t.is_punct(op))
+                    .copied()
+            }) else {
+                return lhs;
+            };
+            self.pos += 1;
+            let rhs = self.binary(prec);
+            lhs = match op {
+                "||" => (lhs != 0 || rhs != 0) as i64,
+                "&&" => (lhs != 0 && rhs != 0) as i64,
+                "|" => lhs | rhs,
+                "^" => lhs ^ rhs,
+                "&" => lhs & rhs,
+                "==" => (lhs == rhs) as i64,
+                "!=" => (lhs != rhs) as i64,
+                "<" => (lhs < rhs) as i64,
+                ">" => (lhs > rhs) as i64,
+                "<=" => (lhs <= rhs) as i64,
+                ">=" => (lhs >= rhs) as i64,
+                "<<" => lhs.wrapping_shl(rhs as u32),
+                ">>" => lhs.wrapping_shr(rhs as u32),
+                "+" => lhs.wrapping_add(rhs),
+                "-" => lhs.wrapping_sub(rhs),
+                "*" => lhs.wrapping_mul(rhs),
+                "/" => lhs.checked_div(rhs).unwrap_or(0),
+                _ => lhs.checked_rem(rhs).unwrap_or(0),
+            };
+        }
+    }
+
+    fn unary(&mut self) -> i64 {
+        if self.eat("!") {
+            return (self.unary() == 0) as i64;
+        }
+        if self.eat("~") {
+            return !self.unary();
+        }
+        if self.eat("-") {
+            return self.unary().wrapping_neg();
+        }
+        if self.eat("+") {
+            return self.unary();
+        }
+        if self.eat("(") {
+            let value = self.ternary();
+            self.eat(")");
+            return value;
+        }
+        let Some(token) = self.peek() else {
+            return 0;
+        };
+        let value = match &token.kind {
+            TokenKind::Number(n) => parse_int(n).unwrap_or(0),
+            TokenKind::Char(c) => c.chars().last().map_or(0, |c| c as i64),
+            _ => 0,
+        };
+        self.pos += 1;
+        value
+    }
+}
+
+/// Parses a C integer literal, ignoring `u`/`l` suffixes
+pub fn parse_int(text: &str) -> Option<i64> {
+    let text = text.trim_end_matches(['u', 'U', 'l', 'L']);
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()
+    } else if text.len() > 1 && text.starts_with('0') {
+        i64::from_str_radix(&text[1..], 8).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preprocess(source: &str) -> String {
+        let tokens = Preprocessor::new(Vec::new()).process_source(source, Path::new("keymap.c"));
+        tokens.iter().map(Token::text).collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn define_order() {
+        let source = "#define FOO KC_A\nLAYOUT(FOO)\n#undef FOO\n#define FOO KC_Z\nFOO";
+        assert_eq!(preprocess(source), "LAYOUT ( KC_A ) KC_Z");
+    }
+
+    #[test]
+    fn object_macros() {
+        assert_eq!(preprocess("#define A B + 1\n#define B 2\nA"), "2 + 1");
+        // a macro doesn't expand inside itself
+        assert_eq!(preprocess("#define A A + 1\nA"), "A + 1");
+        // without the `(` right after the name it's an object-like macro
+        assert_eq!(preprocess("#define A (x)\nA"), "( x )");
+        assert_eq!(preprocess("// a comment\n#define A /* b */ \\\n  KC_A\nA"), "KC_A");
+    }
+
+    #[test]
+    fn function_macros() {
+        assert_eq!(preprocess("#define F(a, b) b a\nF(KC_A, LT(1, KC_B))"), "LT ( 1 , KC_B ) KC_A");
+        assert_eq!(
+            preprocess("#define LAYOUT_wrapper(...) LAYOUT(__VA_ARGS__)\nLAYOUT_wrapper(KC_A,\n KC_B)"),
+            "LAYOUT ( KC_A , KC_B )"
+        );
+        assert_eq!(preprocess("#define F(x, ...) x: __VA_ARGS__\nF(1, 2, 3)"), "1 : 2 , 3");
+        // without arguments a function-like macro is left alone
+        assert_eq!(preprocess("#define F(x) x\nF + 1"), "F + 1");
+    }
+
+    #[test]
+    fn stringify_and_paste() {
+        assert_eq!(preprocess("#define S(x) #x\nS(KC_A)"), "\"KC_A\"");
+        assert_eq!(preprocess("#define K(x) KC_ ## x\nK(A)"), "KC_A");
+        assert_eq!(preprocess("#define L(n) _L ## n\n#define _L2 KC_B\nL(2)"), "KC_B");
+    }
+
+    #[test]
+    fn conditionals() {
+        let source = "
+            #define A 2
+            #if A > 1
+            #  ifdef B
+            no1
+            #  elif defined(A) && !defined B
+            yes1
+            #  else
+            no2
+            #  endif
+            #elif 1
+            no3
+            #else
+            no4
+            #endif
+            #ifndef A
+            no5
+            #elif A == 2
+            yes2
+            #endif
+            #if 0
+            #  if 1
+            no6
+            #  else
+            no7
+            #  endif
+            #else
+            yes3
+            #endif";
+        assert_eq!(preprocess(source), "yes1 yes2 yes3");
+    }
+
+    #[test]
+    fn ints() {
+        assert_eq!(parse_int("0x5220"), Some(0x5220));
+        assert_eq!(parse_int("0XfF"), Some(255));
+        assert_eq!(parse_int("0b1010"), Some(10));
+        assert_eq!(parse_int("017"), Some(15));
+        assert_eq!(parse_int("0"), Some(0));
+        assert_eq!(parse_int("42"), Some(42));
+        assert_eq!(parse_int("10UL"), Some(10));
+        assert_eq!(parse_int("0x10u"), Some(16));
+        assert_eq!(parse_int("09"), None);
+        assert_eq!(parse_int("1.5"), None);
+    }
+}
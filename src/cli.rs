@@ -0,0 +1,82 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+
+/// Live QMK layer visualiser
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(flatten)]
+    pub selection: Selection,
+
+    /// TOML or JSON file with legend overrides, defaults to `qmk-visualiser/legends.toml` in
+    /// the config dir
+    #[arg(long, global = true, value_name = "PATH")]
+    pub legends: Option<PathBuf>,
+
+    /// TOML or JSON file choosing console presets and adding line patterns, defaults to
+    /// `qmk-visualiser/console.toml` in the config dir
+    #[arg(long, global = true, value_name = "PATH")]
+    pub console_config: Option<PathBuf>,
+
+    /// Read the console of the keyboard with this USB id, e.g. `feed:6060`
+    #[arg(long, global = true, value_name = "VID:PID")]
+    pub device: Option<String>,
+
+    /// Read the console from a hidraw node, file or FIFO instead of looking for a keyboard
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "device")]
+    pub console_path: Option<PathBuf>,
+
+    /// Where console lines come from: `qmk`, `hid`, `stdin` (or `-`), `file:PATH`,
+    /// `tcp:ADDR` or `unix:PATH`. Defaults to the keyboard's console, or `qmk console`.
+    #[arg(long, global = true, value_name = "SOURCE", conflicts_with_all = ["device", "console_path"])]
+    pub source: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Which keyboard, keymap and layout to load
+#[derive(Args, Debug, Clone)]
+pub struct Selection {
+    /// Path to the qmk_firmware checkout, defaults to `QMK_HOME` or `user.qmk_home` from qmk.ini
+    #[arg(long, global = true, value_name = "PATH")]
+    pub qmk_home: Option<PathBuf>,
+
+    /// Keyboard name relative to `keyboards/`, e.g. `crkbd/rev1`, defaults to `user.keyboard`
+    #[arg(short = 'k', long, global = true)]
+    pub keyboard: Option<String>,
+
+    /// Keymap folder name inside `keymaps/`, defaults to `user.keymap`
+    #[arg(short = 'm', long, global = true)]
+    pub keymap: Option<String>,
+
+    /// A keymap.c, keymap.json, Vial .vil or VIA backup to load instead of looking the keymap
+    /// up, the `keyboard` of a keymap.json is used when no keyboard is given
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "keymap")]
+    pub keymap_file: Option<PathBuf>,
+
+    /// Read the keymap out of the keyboard's EEPROM over VIA instead of a keymap file, for
+    /// boards with a dynamic keymap. `--device` picks the keyboard if there are several.
+    #[arg(long, global = true, conflicts_with_all = ["keymap", "keymap_file"])]
+    pub via: bool,
+
+    /// Layout macro used in the keymap, e.g. `LAYOUT_split_3x6_3`
+    #[arg(short = 'l', long, global = true, value_name = "MACRO")]
+    pub layout: Option<String>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Open the visualiser window and follow the keyboard's console (default)
+    View,
+    /// List the layout macros the keyboard defines
+    Layouts,
+    /// Print the parsed keymap layers and exit
+    Dump,
+    /// Convert keycodes between their 16-bit value and name, e.g. `0x5220` or `LT(1, KC_SPC)`
+    Keycode {
+        #[arg(required = true)]
+        keycodes: Vec<String>,
+    },
+}
@@ -0,0 +1,47 @@
+use std::path::{Path, PathBuf};
+
+use json::JsonValue;
+
+use crate::hjson;
+use crate::qmk_config;
+
+/// `<config dir>/qmk-visualiser/<name>.toml` or `.json`, whichever exists
+pub fn find(name: &str) -> Option<PathBuf> {
+    let dir = qmk_config::config_dir()?.join("qmk-visualiser");
+    ["toml", "json"]
+        .into_iter()
+        .map(|ext| dir.join(format!("{}.{}", name, ext)))
+        .find(|path| path.is_file())
+}
+
+/// Reads a TOML or JSON (or Hjson) settings file into a `JsonValue`
+pub fn read(path: &Path) -> Result<JsonValue, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+    if path.extension().is_some_and(|ext| ext == "toml") {
+        let table = text
+            .parse::<toml::Table>()
+            .map_err(|e| format!("can't parse {}: {}", path.display(), e))?;
+        Ok(toml_to_json(&toml::Value::Table(table)))
+    } else {
+        hjson::parse(&text).map_err(|e| format!("can't parse {}: {}", path.display(), e))
+    }
+}
+
+fn toml_to_json(value: &toml::Value) -> JsonValue {
+    match value {
+        toml::Value::String(s) => JsonValue::String(s.clone()),
+        toml::Value::Integer(n) => (*n).into(),
+        toml::Value::Float(n) => (*n).into(),
+        toml::Value::Boolean(b) => JsonValue::Boolean(*b),
+        toml::Value::Datetime(d) => JsonValue::String(d.to_string()),
+        toml::Value::Array(items) => JsonValue::Array(items.iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => {
+            let mut object = JsonValue::new_object();
+            for (key, value) in table {
+                object[key.as_str()] = toml_to_json(value);
+            }
+            object
+        }
+    }
+}
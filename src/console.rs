@@ -0,0 +1,276 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use json::JsonValue;
+use regex::{Captures, Regex};
+
+use crate::config_file;
+use crate::hjson;
+use crate::layer_state::LayerState;
+use crate::mods::Mods;
+
+/// Something the firmware told us about its state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsoleEvent {
+    Layers(u32),
+    DefaultLayers(u32),
+    Key { row: u8, col: u8, pressed: bool },
+    Mods(Mods),
+    CapsWord(bool),
+    Label(String),
+}
+
+/// Everything the window shows besides the keymap itself
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsoleState {
+    pub layers: LayerState,
+    pub mods: Mods,
+    pub caps_word: bool,
+    pub pressed: HashSet<(u8, u8)>, // matrix row and column
+    pub label: Option<String>,
+}
+
+impl ConsoleState {
+    /// Returns whether anything changed
+    pub fn apply(&mut self, event: ConsoleEvent) -> bool {
+        let before = self.clone();
+        match event {
+            ConsoleEvent::Layers(mask) => self.layers.layers = mask,
+            ConsoleEvent::DefaultLayers(mask) => self.layers.default_layers = mask,
+            ConsoleEvent::Key { row, col, pressed: true } => {
+                self.pressed.insert((row, col));
+            }
+            ConsoleEvent::Key { row, col, pressed: false } => {
+                self.pressed.remove(&(row, col));
+            }
+            ConsoleEvent::Mods(mods) => self.mods = mods,
+            ConsoleEvent::CapsWord(on) => self.caps_word = on,
+            ConsoleEvent::Label(text) => self.label = Some(text).filter(|t| !t.is_empty()),
+        }
+        *self != before
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EventKind {
+    Layers,
+    DefaultLayers,
+    Key,
+    Mods,
+    CapsWord,
+    Label,
+}
+
+impl EventKind {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "layers" => EventKind::Layers,
+            "default_layers" => EventKind::DefaultLayers,
+            "key" => EventKind::Key,
+            "mods" => EventKind::Mods,
+            "caps_word" => EventKind::CapsWord,
+            "label" => EventKind::Label,
+            _ => return None,
+        })
+    }
+}
+
+/// A regex for console lines and the event its named captures describe:
+///
+/// - `layers`, `default_layers`: `mask` in hex, `bits` as `0`/`1` with layer 0 first, or the
+///   number of a single `layer`
+/// - `key`: `row`, `col` and optionally `pressed` (`1`, `true`, `down`, ...), pressed if absent
+/// - `mods`: `mask`, an 8-bit HID modifier byte in hex
+/// - `caps_word`: `on`
+/// - `label`: `text`, any status text, an empty one clears it
+#[derive(Debug, Clone)]
+struct Pattern {
+    event: EventKind,
+    regex: Regex,
+}
+
+impl Pattern {
+    fn event(&self, captures: &Captures) -> Option<ConsoleEvent> {
+        let get = |name: &str| captures.name(name).map(|m| m.as_str());
+        let number = |name: &str| get(name)?.parse::<u8>().ok();
+        Some(match self.event {
+            EventKind::Layers => ConsoleEvent::Layers(layer_mask(captures)?),
+            EventKind::DefaultLayers => ConsoleEvent::DefaultLayers(layer_mask(captures)?),
+            EventKind::Key => ConsoleEvent::Key {
+                row: number("row")?,
+                col: number("col")?,
+                pressed: get("pressed").is_none_or(is_true),
+            },
+            EventKind::Mods => ConsoleEvent::Mods(Mods(parse_hex(get("mask")?)? as u8)),
+            EventKind::CapsWord => ConsoleEvent::CapsWord(is_true(get("on")?)),
+            EventKind::Label => ConsoleEvent::Label(get("text")?.trim().to_string()),
+        })
+    }
+}
+
+fn layer_mask(captures: &Captures) -> Option<u32> {
+    if let Some(mask) = captures.name("mask") {
+        return parse_hex(mask.as_str());
+    }
+    if let Some(bits) = captures.name("bits") {
+        return Some(
+            bits.as_str()
+                .chars()
+                .take(32)
+                .enumerate()
+                .filter(|(_, c)| *c == '1')
+                .fold(0, |mask, (i, _)| mask | (1 << i)),
+        );
+    }
+    let layer = captures.name("layer")?.as_str().parse::<u32>().ok()?;
+    1u32.checked_shl(layer)
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    let hex = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .unwrap_or(text);
+    u32::from_str_radix(hex, 16).ok()
+}
+
+fn is_true(text: &str) -> bool {
+    matches!(
+        text.to_ascii_lowercase().as_str(),
+        "1" | "true" | "on" | "yes" | "down" | "pressed"
+    )
+}
+
+/// The console line formats to recognise
+#[derive(Debug, Clone, Default)]
+pub struct ConsoleProtocol {
+    patterns: Vec<Pattern>,
+}
+
+const BUILTIN_PRESETS: &str = include_str!("console_presets.hjson");
+
+impl ConsoleProtocol {
+    /// The built-in presets, narrowed down and extended by the settings file at `path` or
+    /// `console.toml`/`console.json` in the visualiser's config dir:
+    ///
+    /// ```toml
+    /// presets = ["layers", "keylog"]  # built-in presets to keep, all if left out
+    /// [[patterns]]
+    /// event = "layers"
+    /// regex = 'layer: (?P<layer>\d+)'
+    /// ```
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let presets = hjson::parse(BUILTIN_PRESETS).expect("built-in console presets are valid");
+        let path = path.map(Path::to_path_buf).or_else(|| config_file::find("console"));
+        let settings = match &path {
+            Some(path) => config_file::read(path)?,
+            None => JsonValue::new_object(),
+        };
+        let source = |e: String| match &path {
+            Some(path) => format!("{}: {}", path.display(), e),
+            None => e,
+        };
+
+        let mut protocol = ConsoleProtocol::default();
+        let selected = settings["presets"]
+            .members()
+            .filter_map(JsonValue::as_str)
+            .collect::<Vec<_>>();
+        for name in &selected {
+            if !presets.has_key(name) {
+                let known = presets.entries().map(|(name, _)| name).collect::<Vec<_>>();
+                return Err(source(format!(
+                    "unknown console preset `{}`, available: {}",
+                    name,
+                    known.join(", ")
+                )));
+            }
+        }
+        for (name, patterns) in presets.entries() {
+            if settings["presets"].is_array() && !selected.contains(&name) {
+                continue;
+            }
+            protocol.add_patterns(patterns).expect("built-in console presets are valid");
+        }
+        protocol.add_patterns(&settings["patterns"]).map_err(source)?;
+        Ok(protocol)
+    }
+
+    fn add_patterns(&mut self, patterns: &JsonValue) -> Result<(), String> {
+        for pattern in patterns.members() {
+            let event = pattern["event"].as_str().unwrap_or_default();
+            let event = EventKind::from_name(event)
+                .ok_or_else(|| format!("unknown console event `{}`", event))?;
+            let regex = pattern["regex"].as_str().ok_or("console pattern without a regex")?;
+            let regex = Regex::new(regex).map_err(|e| format!("invalid console pattern: {}", e))?;
+            self.patterns.push(Pattern { event, regex });
+        }
+        Ok(())
+    }
+
+    /// Events of every pattern matching the line
+    pub fn parse_line(&self, line: &str) -> Vec<ConsoleEvent> {
+        self.patterns
+            .iter()
+            .filter_map(|pattern| pattern.event(&pattern.regex.captures(line)?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(line: &str) -> Vec<ConsoleEvent> {
+        ConsoleProtocol::load(None).unwrap().parse_line(line)
+    }
+
+    #[test]
+    fn builtin_presets() {
+        assert_eq!(
+            events("layer_state: 00000000(0) to 00000002(1)"),
+            [ConsoleEvent::Layers(0b10)]
+        );
+        assert_eq!(events("layer_state: 0x1a"), [ConsoleEvent::Layers(0x1a)]);
+        assert_eq!(
+            events("default_layer_state: 00000001(0) to 00000004(2)"),
+            [ConsoleEvent::DefaultLayers(0b100)]
+        );
+        assert_eq!(
+            events("KL: kc: 0x0004, col:  1, row:  0, pressed: 1, time: 1234, int: 0, count: 0"),
+            [ConsoleEvent::Key { row: 0, col: 1, pressed: true }]
+        );
+        assert_eq!(
+            events("KL: kc: 0x0004, col: 11, row: 3, pressed: 0, time: 1300, int: 0, count: 0"),
+            [ConsoleEvent::Key { row: 3, col: 11, pressed: false }]
+        );
+        assert_eq!(events("LAYERS: 0100"), [ConsoleEvent::Layers(0b10)]);
+        assert_eq!(events("LAYERS: 1000000000000000000000000000000011"), [ConsoleEvent::Layers(1)]);
+        assert_eq!(events("MODS: 0x03"), [ConsoleEvent::Mods(Mods(0x03))]);
+        assert!(events("unrelated output").is_empty());
+    }
+
+    #[test]
+    fn apply() {
+        let protocol = ConsoleProtocol::load(None).unwrap();
+        let mut state = ConsoleState::default();
+        let apply = |state: &mut ConsoleState, line: &str| {
+            protocol
+                .parse_line(line)
+                .into_iter()
+                .fold(false, |changed, event| state.apply(event) | changed)
+        };
+        assert!(apply(&mut state, "layer_state: 00000000(0) to 00000002(1)"));
+        assert!(!apply(&mut state, "layer_state: 00000000(0) to 00000002(1)"));
+        assert!(apply(&mut state, "KL: kc: 0x0004, col:  1, row:  0, pressed: 1, time: 10, int: 0, count: 0"));
+        assert!(apply(&mut state, "LABEL: hello"));
+        assert_eq!(state.layers.layers, 0b10);
+        assert_eq!(state.pressed, HashSet::from([(0, 1)]));
+        assert_eq!(state.label.as_deref(), Some("hello"));
+
+        assert!(apply(&mut state, "KL: kc: 0x0004, col:  1, row:  0, pressed: 0, time: 20, int: 0, count: 0"));
+        assert!(apply(&mut state, "LABEL:"));
+        assert!(state.pressed.is_empty());
+        assert_eq!(state.label, None);
+    }
+}
@@ -0,0 +1,139 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::c_lexer::Token;
+
+/// A stretch of a source line, line and column counted from 1 like compilers do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub file: Arc<Path>,
+    pub line: usize,
+    pub col: usize,
+    /// In characters, at least 1
+    pub len: usize,
+}
+
+impl Span {
+    /// From the first token to the last one on the same line. Tokens out of a macro expansion
+    /// sit on the macro's name, so that is what gets covered for them.
+    pub fn of(tokens: &[Token]) -> Option<Span> {
+        let first = tokens.first()?;
+        let end = tokens
+            .iter()
+            .filter(|t| t.line == first.line && t.file == first.file)
+            .map(|t| t.col + t.origin.as_ref().map_or_else(|| t.text().chars().count(), |o| o.len()))
+            .max()
+            .unwrap_or(first.col + 1);
+        Some(Span {
+            file: first.file.clone()?,
+            line: first.line,
+            col: first.col,
+            len: end.saturating_sub(first.col).max(1),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// An error or warning, optionally pointing into a source file
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    pub note: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            span,
+            note: None,
+        }
+    }
+
+    pub fn warning(message: impl Into<String>, span: Option<Span>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Self::error(message, span)
+        }
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.note = Some(note.into());
+        self
+    }
+}
+
+impl From<String> for Diagnostic {
+    fn from(message: String) -> Self {
+        Diagnostic::error(message, None)
+    }
+}
+
+impl From<&str> for Diagnostic {
+    fn from(message: &str) -> Self {
+        Diagnostic::error(message, None)
+    }
+}
+
+/// Like rustc: the message, the location, and the source line with the span underlined
+///
+/// ```text
+/// warning: unknown keycode `KC_FOO`
+///   --> keyboards/planck/keymaps/mine/keymap.c:12:9
+///    |
+/// 12 |         KC_FOO, KC_B,
+///    |         ^^^^^^
+/// ```
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}", severity, self.message)?;
+        let gutter = self.span.as_ref().map_or(0, |s| s.line.to_string().len());
+        if let Some(span) = &self.span {
+            write!(
+                f,
+                "\n{:gutter$}--> {}:{}:{}",
+                "",
+                span.file.display(),
+                span.line,
+                span.col
+            )?;
+            // the file may have changed or gone since, then there's just the location
+            let source = std::fs::read_to_string(&span.file).ok();
+            if let Some(line) = source.as_deref().and_then(|s| s.lines().nth(span.line - 1)) {
+                // tabs would throw off the carets, rustc shows them as 4 spaces too
+                let untab = |text: &str| text.replace('\t', "    ");
+                let before = line.chars().take(span.col - 1).collect::<String>();
+                let marked = line
+                    .chars()
+                    .skip(span.col - 1)
+                    .take(span.len)
+                    .collect::<String>();
+                write!(f, "\n{:gutter$} |", "")?;
+                write!(f, "\n{} | {}", span.line, untab(line))?;
+                write!(
+                    f,
+                    "\n{:gutter$} | {}{}",
+                    "",
+                    " ".repeat(untab(&before).chars().count()),
+                    "^".repeat(untab(&marked).chars().count().max(1))
+                )?;
+            }
+        }
+        if let Some(note) = &self.note {
+            write!(f, "\n{:gutter$} = note: {}", "", note)?;
+        }
+        Ok(())
+    }
+}
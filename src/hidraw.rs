@@ -0,0 +1,264 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// QMK's console interface, see `tmk_core/protocol/usb_descriptor.c`
+const CONSOLE_USAGE_PAGE: u32 = 0xFF31;
+const CONSOLE_USAGE: u32 = 0x74;
+/// The raw HID interface VIA talks to
+const RAW_USAGE_PAGE: u32 = 0xFF60;
+const RAW_USAGE: u32 = 0x61;
+
+/// A keyboard's HID interface under `/dev`
+#[derive(Debug, Clone)]
+pub struct HidDevice {
+    pub path: PathBuf,
+    pub vid: u16,
+    pub pid: u16,
+    pub name: String,
+}
+
+impl std::fmt::Display for HidDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({:04x}:{:04x}) at {}", self.name, self.vid, self.pid, self.path.display())
+    }
+}
+
+/// Every hidraw node whose report descriptor declares the QMK console usage
+pub fn find_consoles() -> Vec<HidDevice> {
+    find_devices(CONSOLE_USAGE_PAGE, CONSOLE_USAGE)
+}
+
+/// Every hidraw node of a keyboard's raw HID interface
+pub fn find_raw_hid() -> Vec<HidDevice> {
+    find_devices(RAW_USAGE_PAGE, RAW_USAGE)
+}
+
+fn find_devices(usage_page: u32, usage: u32) -> Vec<HidDevice> {
+    let Ok(entries) = std::fs::read_dir("/sys/class/hidraw") else {
+        return Vec::new();
+    };
+    let mut devices = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| {
+            let sys = entry.path().join("device");
+            let descriptor = std::fs::read(sys.join("report_descriptor")).ok()?;
+            if !has_usage(&descriptor, usage_page, usage) {
+                return None;
+            }
+            let uevent = std::fs::read_to_string(sys.join("uevent")).ok()?;
+            let (vid, pid) = uevent_ids(&uevent)?;
+            let name = uevent
+                .lines()
+                .find_map(|line| line.strip_prefix("HID_NAME="))
+                .unwrap_or("unknown")
+                .to_string();
+            Some(HidDevice {
+                path: Path::new("/dev").join(entry.file_name()),
+                vid,
+                pid,
+                name,
+            })
+        })
+        .collect::<Vec<_>>();
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+    devices
+}
+
+/// Parses `VID:PID` in hex, like `feed:6060`
+pub fn parse_vid_pid(text: &str) -> Result<(u16, u16), String> {
+    let parse = |hex: &str| u16::from_str_radix(hex.trim_start_matches("0x"), 16).ok();
+    match text.split_once(':') {
+        Some((vid, pid)) => match (parse(vid), parse(pid)) {
+            (Some(vid), Some(pid)) => Ok((vid, pid)),
+            _ => Err(format!("invalid device `{}`, expected VID:PID in hex", text)),
+        },
+        None => Err(format!("invalid device `{}`, expected VID:PID in hex", text)),
+    }
+}
+
+/// The console of the keyboard with this VID:PID, or the only console if none is given
+pub fn select_console(vid_pid: Option<(u16, u16)>) -> Result<HidDevice, String> {
+    select(find_consoles(), vid_pid, "QMK console", "CONSOLE_ENABLE")
+}
+
+/// The raw HID interface of the keyboard with this VID:PID, or the only one if none is given
+pub fn select_raw_hid(vid_pid: Option<(u16, u16)>) -> Result<HidDevice, String> {
+    select(find_raw_hid(), vid_pid, "VIA raw HID interface", "VIA_ENABLE")
+}
+
+fn select(
+    devices: Vec<HidDevice>,
+    vid_pid: Option<(u16, u16)>,
+    what: &str,
+    feature: &str,
+) -> Result<HidDevice, String> {
+    let list = || {
+        devices
+            .iter()
+            .map(|c| format!("\n  {}", c))
+            .collect::<String>()
+    };
+    match vid_pid {
+        Some((vid, pid)) => devices
+            .iter()
+            .find(|c| c.vid == vid && c.pid == pid)
+            .cloned()
+            .ok_or_else(|| {
+                format!("no {} with id {:04x}:{:04x}, found:{}", what, vid, pid, list())
+            }),
+        None => match devices.as_slice() {
+            [] => Err(format!("no {} found, is {} on?", what, feature)),
+            [device] => Ok(device.clone()),
+            _ => Err(format!("several {}s found, pick one with --device:{}", what, list())),
+        },
+    }
+}
+
+// HID_ID=0003:0000FEED:00006060
+fn uevent_ids(uevent: &str) -> Option<(u16, u16)> {
+    let id = uevent.lines().find_map(|line| line.strip_prefix("HID_ID="))?;
+    let mut parts = id.split(':').skip(1);
+    let vid = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let pid = u32::from_str_radix(parts.next()?, 16).ok()?;
+    Some((vid as u16, pid as u16))
+}
+
+/// Walks the short items of a HID report descriptor looking for a usage on a usage page
+fn has_usage(descriptor: &[u8], usage_page: u32, usage: u32) -> bool {
+    let mut page = 0;
+    let mut pos = 0;
+    while pos < descriptor.len() {
+        let prefix = descriptor[pos];
+        if prefix == 0xFE {
+            // long item, its data size is in the next byte
+            let size = descriptor.get(pos + 1).copied().unwrap_or(0) as usize;
+            pos += 3 + size;
+            continue;
+        }
+        let size = match prefix & 0x03 {
+            3 => 4,
+            n => n as usize,
+        };
+        let Some(data) = descriptor.get(pos + 1..pos + 1 + size) else {
+            break;
+        };
+        let value = data
+            .iter()
+            .rev()
+            .fold(0u32, |value, &byte| (value << 8) | byte as u32);
+        match prefix & 0xFC {
+            0x04 => page = value, // Usage Page
+            0x08 if size == 4 && value == (usage_page << 16) | usage => return true, // extended Usage
+            0x08 if page == usage_page && value == usage => return true, // Usage
+            _ => {}
+        }
+        pos += 1 + size;
+    }
+    false
+}
+
+/// The text in the console's reports. QMK pads every report with NULs, those are dropped so
+/// the same reader works for a hidraw node and for plain text from a file or FIFO.
+pub struct ConsoleReports<R> {
+    inner: R,
+    report: [u8; 64],
+}
+
+/// Opens a hidraw node or file, `write` for sending reports too
+pub fn open(path: &Path, write: bool) -> Result<File, String> {
+    File::options()
+        .read(true)
+        .write(write)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::PermissionDenied => format!(
+                "can't open {}: {} (a udev rule for the keyboard gives access)",
+                path.display(),
+                e
+            ),
+            _ => format!("can't open {}: {}", path.display(), e),
+        })
+}
+
+impl ConsoleReports<File> {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = open(path, false)?;
+        Ok(ConsoleReports {
+            inner: file,
+            report: [0; 64],
+        })
+    }
+}
+
+impl<R: Read> Read for ConsoleReports<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let len = buf.len().min(self.report.len());
+            let n = self.inner.read(&mut self.report[..len])?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut written = 0;
+            for &byte in self.report[..n].iter().filter(|&&b| b != 0) {
+                buf[written] = byte;
+                written += 1;
+            }
+            // an all padding report isn't the end of the stream
+            if written > 0 {
+                return Ok(written);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // QMK's console interface
+    const CONSOLE: &[u8] = &[
+        0x06, 0x31, 0xFF, 0x09, 0x74, 0xA1, 0x01, 0x09, 0x75, 0x15, 0x00, 0x26, 0xFF, 0x00, 0x95,
+        0x20, 0x75, 0x08, 0x81, 0x02, 0xC0,
+    ];
+    // QMK's raw HID interface, used by VIA
+    const RAW: &[u8] = &[
+        0x06, 0x60, 0xFF, 0x09, 0x61, 0xA1, 0x01, 0x09, 0x62, 0x15, 0x00, 0x26, 0xFF, 0x00, 0x95,
+        0x20, 0x75, 0x08, 0x81, 0x02, 0x09, 0x63, 0x15, 0x00, 0x26, 0xFF, 0x00, 0x95, 0x20, 0x75,
+        0x08, 0x91, 0x02, 0xC0,
+    ];
+
+    #[test]
+    fn usages() {
+        assert!(has_usage(CONSOLE, CONSOLE_USAGE_PAGE, CONSOLE_USAGE));
+        assert!(!has_usage(CONSOLE, RAW_USAGE_PAGE, RAW_USAGE));
+        assert!(has_usage(RAW, RAW_USAGE_PAGE, RAW_USAGE));
+        assert!(!has_usage(RAW, CONSOLE_USAGE_PAGE, CONSOLE_USAGE));
+        // a usage from another page doesn't count
+        assert!(!has_usage(CONSOLE, RAW_USAGE_PAGE, 0x75));
+    }
+
+    #[test]
+    fn extended_usage() {
+        // 4-byte usage carrying its page in the high half, no Usage Page item
+        let descriptor = [0x0B, 0x74, 0x00, 0x31, 0xFF, 0xA1, 0x01, 0xC0];
+        assert!(has_usage(&descriptor, CONSOLE_USAGE_PAGE, CONSOLE_USAGE));
+        assert!(!has_usage(&descriptor, RAW_USAGE_PAGE, CONSOLE_USAGE));
+    }
+
+    #[test]
+    fn long_item() {
+        // the long item's data looks like a Usage item but has to be skipped
+        let mut descriptor = vec![0x06, 0x31, 0xFF, 0xFE, 0x02, 0x10, 0x09, 0x74];
+        assert!(!has_usage(&descriptor, CONSOLE_USAGE_PAGE, CONSOLE_USAGE));
+        descriptor.extend([0x09, 0x74]);
+        assert!(has_usage(&descriptor, CONSOLE_USAGE_PAGE, CONSOLE_USAGE));
+    }
+
+    #[test]
+    fn truncated() {
+        assert!(!has_usage(&[0x06, 0x31], CONSOLE_USAGE_PAGE, CONSOLE_USAGE));
+        assert!(!has_usage(&[0x06, 0x31, 0xFF, 0xFE], CONSOLE_USAGE_PAGE, CONSOLE_USAGE));
+        assert!(!has_usage(&[], CONSOLE_USAGE_PAGE, CONSOLE_USAGE));
+    }
+}
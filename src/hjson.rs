@@ -0,0 +1,230 @@
+use json::JsonValue;
+
+/// Parses the Hjson used by qmk_firmware's `data/` folder into a `JsonValue`: comments,
+/// unquoted keys, quoteless strings and optional commas on top of plain JSON.
+pub fn parse(source: &str) -> Result<JsonValue, String> {
+    let mut parser = Parser {
+        chars: source.chars().collect(),
+        pos: 0,
+    };
+    parser.skip_whitespace();
+    // the braces around the root object are optional
+    let value = if parser.peek() == Some('{') || parser.peek() == Some('[') {
+        parser.value()?
+    } else {
+        parser.members(None)?
+    };
+    parser.skip_whitespace();
+    if parser.pos < parser.chars.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn error(&self, message: &str) -> String {
+        let line = self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count()
+            + 1;
+        format!("line {}: {}", line, message)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || c == ',' {
+                self.pos += 1;
+            } else if c == '#' || (c == '/' && self.chars.get(self.pos + 1) == Some(&'/')) {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c == '/' && self.chars.get(self.pos + 1) == Some(&'*') {
+                self.pos += 2;
+                while self.pos < self.chars.len()
+                    && !(self.chars[self.pos] == '*' && self.chars.get(self.pos + 1) == Some(&'/'))
+                {
+                    self.pos += 1;
+                }
+                self.pos += 2;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<JsonValue, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                self.members(Some('}'))
+            }
+            Some('[') => {
+                self.pos += 1;
+                let mut array = JsonValue::new_array();
+                loop {
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(']') => {
+                            self.pos += 1;
+                            return Ok(array);
+                        }
+                        None => return Err(self.error("unterminated array")),
+                        _ => array.push(self.value()?).unwrap(),
+                    }
+                }
+            }
+            Some(q @ ('"' | '\'')) => {
+                self.pos += 1;
+                Ok(JsonValue::String(self.quoted(q)?))
+            }
+            Some(_) => self.quoteless(),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn members(&mut self, close: Option<char>) -> Result<JsonValue, String> {
+        let mut object = JsonValue::new_object();
+        loop {
+            self.skip_whitespace();
+            match (self.peek(), close) {
+                (Some(c), Some(close)) if c == close => {
+                    self.pos += 1;
+                    return Ok(object);
+                }
+                (None, None) => return Ok(object),
+                (None, Some(_)) => return Err(self.error("unterminated object")),
+                _ => {}
+            }
+            let key = match self.peek() {
+                Some(q @ ('"' | '\'')) => {
+                    self.pos += 1;
+                    self.quoted(q)?
+                }
+                _ => {
+                    let start = self.pos;
+                    while self.peek().is_some_and(|c| c != ':' && !c.is_whitespace()) {
+                        self.pos += 1;
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            self.skip_whitespace();
+            if self.peek() != Some(':') {
+                return Err(self.error(&format!("expected `:` after key {}", key)));
+            }
+            self.pos += 1;
+            let value = self.value()?;
+            object[key.as_str()] = value;
+        }
+    }
+
+    fn quoted(&mut self, quote: char) -> Result<String, String> {
+        let mut text = String::new();
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            match c {
+                c if c == quote => return Ok(text),
+                '\\' => {
+                    let Some(escaped) = self.peek() else {
+                        return Err(self.error("unterminated string"));
+                    };
+                    self.pos += 1;
+                    match escaped {
+                        'n' => text.push('\n'),
+                        't' => text.push('\t'),
+                        'r' => text.push('\r'),
+                        'b' => text.push('\u{8}'),
+                        'f' => text.push('\u{c}'),
+                        'u' => {
+                            let hex = self.chars[self.pos..(self.pos + 4).min(self.chars.len())]
+                                .iter()
+                                .collect::<String>();
+                            self.pos += 4;
+                            let code = u32::from_str_radix(&hex, 16)
+                                .map_err(|_| self.error("invalid \\u escape"))?;
+                            text.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                        other => text.push(other),
+                    }
+                }
+                c => text.push(c),
+            }
+        }
+    }
+
+    /// Numbers, `true`/`false`/`null` and quoteless strings, which run to the end of the line
+    fn quoteless(&mut self) -> Result<JsonValue, String> {
+        if let Some(c @ ('}' | ']')) = self.peek() {
+            return Err(self.error(&format!("unexpected `{}`", c)));
+        }
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c != '\n' && !(c == ',' || c == '}' || c == ']'))
+        {
+            self.pos += 1;
+        }
+        let text = self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .trim()
+            .to_string();
+        if text.is_empty() {
+            return Err(self.error("expected a value"));
+        }
+        Ok(match text.as_str() {
+            "true" => JsonValue::Boolean(true),
+            "false" => JsonValue::Boolean(false),
+            "null" => JsonValue::Null,
+            _ => match text.parse::<f64>() {
+                Ok(n) => n.into(),
+                Err(_) => JsonValue::String(text),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn hjson() {
+        let value = parse(
+            "# comment\nkeycodes: {\n  \"0x0004\": { key: KC_A, aliases: ['KC_A'] }\n  /* x */ n: 1.5\n}",
+        )
+        .unwrap();
+        assert_eq!(value["keycodes"]["0x0004"]["key"], "KC_A");
+        assert_eq!(value["keycodes"]["0x0004"]["aliases"][0], "KC_A");
+        assert_eq!(value["keycodes"]["n"], 1.5);
+    }
+
+    #[test]
+    fn malformed() {
+        for source in [
+            r#"{"legends": [}]}"#,
+            r#"{"legends": [1, ]"#,
+            r#"{"a": }"#,
+            r#"["a"]]"#,
+            r#"{"a": "b"#,
+            "{a 1}",
+            "[1, 2",
+        ] {
+            assert!(parse(source).is_err(), "{}", source);
+        }
+    }
+}
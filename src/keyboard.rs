@@ -0,0 +1,424 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use json::JsonValue;
+
+use crate::hjson;
+
+/// A keyboard resolved through the qmk_firmware tree, with the `info.json`/`keyboard.json`
+/// files of every folder from `keyboards/` down to the selected revision merged together.
+pub struct Keyboard {
+    pub name: String,
+    /// Keyboard folders from the least to the most specific one
+    pub dirs: Vec<PathBuf>,
+    pub info: JsonValue,
+}
+
+pub fn resolve(qmk_home: &Path, name: &str) -> Result<Keyboard, String> {
+    let keyboards_root = qmk_home.join("keyboards");
+    if !keyboards_root.is_dir() {
+        return Err(format!(
+            "{} is not a qmk_firmware checkout, {} is missing",
+            qmk_home.display(),
+            keyboards_root.display()
+        ));
+    }
+
+    let aliases = keyboard_aliases(qmk_home);
+    let name = keyboard_folder(name.trim_matches('/'), &aliases, |name| {
+        default_folder(&keyboards_root.join(name))
+            .filter(|folder| keyboards_root.join(folder).is_dir())
+    });
+    if !keyboards_root.join(&name).is_dir() {
+        return Err(format!(
+            "keyboard `{}` not found in {}",
+            name,
+            keyboards_root.display()
+        ));
+    }
+
+    let mut dirs = Vec::new();
+    let mut dir = keyboards_root.clone();
+    for part in name.split('/') {
+        dir = dir.join(part);
+        dirs.push(dir.clone());
+    }
+
+    let mut info = JsonValue::new_object();
+    info["layouts"] = JsonValue::new_object();
+    let mut found_any = false;
+    for dir in &dirs {
+        for file in ["info.json", "keyboard.json"] {
+            let path = dir.join(file);
+            if !path.is_file() {
+                continue;
+            }
+            let text = std::fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+            let new_info = json::parse(&text)
+                .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+            merge_info_json(&mut info, new_info, &path);
+            found_any = true;
+        }
+    }
+    if !found_any {
+        return Err(format!(
+            "keyboard `{}` has no info.json or keyboard.json",
+            name
+        ));
+    }
+
+    merge_community_layouts(&mut info, qmk_home);
+
+    Ok(Keyboard { name, dirs, info })
+}
+
+/// Follows renamed keyboards and `DEFAULT_FOLDER`s like `keyboard_folder` in the qmk cli,
+/// e.g. `crkbd` builds `crkbd/rev1`. Stops at the first name seen twice.
+fn keyboard_folder(
+    name: &str,
+    aliases: &HashMap<String, String>,
+    default_folder: impl Fn(&str) -> Option<String>,
+) -> String {
+    let mut name = name.to_string();
+    let mut seen = HashSet::new();
+    while seen.insert(name.clone()) {
+        match aliases.get(&name).cloned().or_else(|| default_folder(&name)) {
+            Some(next) => name = next,
+            None => break,
+        }
+    }
+    name
+}
+
+/// Targets of the keyboards in `data/mappings/keyboard_aliases.hjson`, empty for checkouts
+/// without one
+fn keyboard_aliases(qmk_home: &Path) -> HashMap<String, String> {
+    let path = qmk_home.join("data/mappings/keyboard_aliases.hjson");
+    let Ok(text) = std::fs::read_to_string(&path) else {
+        return HashMap::new();
+    };
+    let aliases = match hjson::parse(&text) {
+        Ok(aliases) => aliases,
+        Err(e) => {
+            eprintln!("warning: failed to parse {}: {}", path.display(), e);
+            return HashMap::new();
+        }
+    };
+    aliases
+        .entries()
+        .filter_map(|(name, alias)| {
+            let target = alias["target"].as_str()?.trim_matches('/');
+            Some((name.to_string(), target.to_string()))
+        })
+        .collect()
+}
+
+/// Reads `DEFAULT_FOLDER` from a keyboard's rules.mk
+fn default_folder(dir: &Path) -> Option<String> {
+    let rules = std::fs::read_to_string(dir.join("rules.mk")).ok()?;
+    rules.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        if key.trim_end_matches([' ', ':', '?']).trim() != "DEFAULT_FOLDER" {
+            return None;
+        }
+        Some(value.trim().trim_matches('/').to_string())
+    })
+}
+
+/// Same merge rules as `merge_info_jsons` in the qmk cli: aliases are combined, keys of an
+/// already known layout are updated in place and everything else is deep merged.
+fn merge_info_json(info: &mut JsonValue, mut new_info: JsonValue, path: &Path) {
+    let new_aliases = new_info.remove("layout_aliases");
+    for (alias, target) in new_aliases.entries() {
+        info["layout_aliases"][alias] = target.clone();
+    }
+
+    let new_layouts = new_info.remove("layouts");
+    for (layout_name, layout) in new_layouts.entries() {
+        let layout_name = info["layout_aliases"][layout_name]
+            .as_str()
+            .unwrap_or(layout_name)
+            .to_string();
+
+        if info["layouts"].has_key(&layout_name) {
+            let existing = &mut info["layouts"][layout_name.as_str()];
+            if existing["layout"].len() != layout["layout"].len() {
+                eprintln!(
+                    "warning: {}: layout {} has {} keys, expected {}",
+                    path.display(),
+                    layout_name,
+                    layout["layout"].len(),
+                    existing["layout"].len()
+                );
+                continue;
+            }
+            for (existing_key, new_key) in existing["layout"]
+                .members_mut()
+                .zip(layout["layout"].members())
+            {
+                for (field, value) in new_key.entries() {
+                    existing_key[field] = value.clone();
+                }
+            }
+        } else if layout["layout"].members().all(|key| key.has_key("matrix")) {
+            info["layouts"][layout_name.as_str()] = layout.clone();
+        } else {
+            eprintln!(
+                "warning: {}: layout {} is missing matrix positions, skipping it",
+                path.display(),
+                layout_name
+            );
+        }
+    }
+
+    deep_update(info, new_info);
+}
+
+fn deep_update(orig: &mut JsonValue, new: JsonValue) {
+    let JsonValue::Object(new) = new else {
+        *orig = new;
+        return;
+    };
+    if !orig.is_object() {
+        *orig = JsonValue::new_object();
+    }
+    for (key, value) in new.iter() {
+        if value.is_object() {
+            deep_update(&mut orig[key], value.clone());
+        } else {
+            orig[key] = value.clone();
+        }
+    }
+}
+
+/// Community layouts the keyboard supports but doesn't define come from `layouts/default/`
+fn merge_community_layouts(info: &mut JsonValue, qmk_home: &Path) {
+    let community = info["community_layouts"]
+        .members()
+        .filter_map(|l| l.as_str().map(str::to_string))
+        .collect::<Vec<_>>();
+    for layout in community {
+        let layout_name = format!("LAYOUT_{}", layout);
+        if info["layouts"].has_key(&layout_name) {
+            continue;
+        }
+        let path = qmk_home
+            .join("layouts")
+            .join("default")
+            .join(&layout)
+            .join("info.json");
+        let Ok(text) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        let Ok(mut default_info) = json::parse(&text) else {
+            eprintln!("warning: failed to parse {}", path.display());
+            continue;
+        };
+        let definition = default_info["layouts"].remove(&layout_name);
+        if definition.is_object() {
+            info["layouts"][layout_name.as_str()] = definition;
+        }
+    }
+}
+
+impl Keyboard {
+    pub fn layout_names(&self) -> Vec<String> {
+        self.info["layouts"]
+            .entries()
+            .map(|(name, _)| name.to_string())
+            .collect()
+    }
+
+    pub fn aliases(&self) -> Vec<(String, String)> {
+        self.info["layout_aliases"]
+            .entries()
+            .filter_map(|(alias, target)| Some((alias.to_string(), target.as_str()?.to_string())))
+            .collect()
+    }
+
+    /// Follows `layout_aliases` to the layout that actually holds the key positions
+    pub fn canonical_layout_name<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        let name = self.info["layout_aliases"][name].as_str().unwrap_or(name);
+        self.info["layouts"].has_key(name).then_some(name)
+    }
+
+    pub fn layout(&self, name: &str) -> Option<&JsonValue> {
+        let name = self.canonical_layout_name(name)?;
+        Some(&self.info["layouts"][name])
+    }
+
+    /// The layout with the most keys, which covers the most matrix positions
+    pub fn largest_layout(&self) -> Option<String> {
+        self.layout_names()
+            .into_iter()
+            .max_by_key(|name| self.info["layouts"][name.as_str()]["layout"].len())
+    }
+
+    /// Rows and columns of the switch matrix, from `matrix_size`, else the pins the way QMK
+    /// works it out, else the highest positions the layouts use
+    pub fn matrix_size(&self) -> (usize, usize) {
+        let explicit = &self.info["matrix_size"];
+        if let (Some(rows), Some(cols)) = (explicit["rows"].as_usize(), explicit["cols"].as_usize()) {
+            return (rows, cols);
+        }
+        let pins = &self.info["matrix_pins"];
+        let from_pins = if pins["direct"].is_array() {
+            Some((pins["direct"].len(), pins["direct"][0].len()))
+        } else if pins["rows"].is_array() && pins["cols"].is_array() {
+            Some((pins["rows"].len(), pins["cols"].len()))
+        } else {
+            None
+        };
+        if let Some((rows, cols)) = from_pins.filter(|&(rows, cols)| rows > 0 && cols > 0) {
+            // each half of a split keyboard scans its own rows
+            let halves = if self.info["split"]["enabled"].as_bool() == Some(true) { 2 } else { 1 };
+            return (rows * halves, cols);
+        }
+        let used = |index: usize| {
+            self.info["layouts"]
+                .entries()
+                .flat_map(|(_, layout)| layout["layout"].members())
+                .filter_map(|key| key["matrix"][index].as_usize())
+                .map(|n| n + 1)
+                .max()
+                .unwrap_or(0)
+        };
+        (used(0), used(1))
+    }
+
+    fn community_layouts(&self) -> Vec<String> {
+        self.info["community_layouts"]
+            .members()
+            .filter_map(|l| l.as_str().map(str::to_string))
+            .collect()
+    }
+
+    /// Folders a keymap called `keymap` may live in, most specific first. A qmk userspace
+    /// overlays qmk_firmware, so its folders are searched before the firmware tree.
+    pub fn keymap_dirs(
+        &self,
+        qmk_home: &Path,
+        userspace: Option<&Path>,
+        keymap: &str,
+    ) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+        if let Some(userspace) = userspace {
+            let parts = self.name.split('/').collect::<Vec<_>>();
+            for depth in (1..=parts.len()).rev() {
+                let mut dir = userspace.join("keyboards");
+                dir.extend(&parts[..depth]);
+                dirs.push(dir.join("keymaps").join(keymap));
+            }
+        }
+        dirs.extend(
+            self.dirs
+                .iter()
+                .rev()
+                .map(|dir| dir.join("keymaps").join(keymap)),
+        );
+        for layout in self.community_layouts() {
+            if let Some(userspace) = userspace {
+                dirs.push(userspace.join("layouts").join(&layout).join(keymap));
+            }
+            dirs.push(
+                qmk_home
+                    .join("layouts")
+                    .join("community")
+                    .join(&layout)
+                    .join(keymap),
+            );
+        }
+        dirs
+    }
+
+    /// Where `#include "..."` and config.h files are looked up for a keymap, most specific first
+    pub fn include_dirs(
+        &self,
+        keymap_c: &Path,
+        qmk_home: &Path,
+        userspace: Option<&Path>,
+        keymap: &str,
+    ) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+        if let Some(dir) = keymap_c.parent() {
+            dirs.push(dir.to_path_buf());
+        }
+        dirs.extend(self.dirs.iter().rev().cloned());
+        if let Some(userspace) = userspace {
+            dirs.push(userspace.join("users").join(keymap));
+        }
+        dirs.push(qmk_home.join("users").join(keymap));
+        dirs.retain(|d| d.is_dir());
+        dirs
+    }
+
+    /// The keymap.c of a keymap, or its keymap.json when there is no keymap.c
+    pub fn find_keymap(
+        &self,
+        qmk_home: &Path,
+        userspace: Option<&Path>,
+        keymap: &str,
+    ) -> Result<PathBuf, String> {
+        let dirs = self.keymap_dirs(qmk_home, userspace, keymap);
+        dirs.iter()
+            .flat_map(|dir| [dir.join("keymap.c"), dir.join("keymap.json")])
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                let searched = dirs
+                    .iter()
+                    .map(|d| format!("\n    {}", d.display()))
+                    .collect::<String>();
+                format!(
+                    "keymap `{}` not found for keyboard `{}`, searched:{}",
+                    keymap, self.name, searched
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyboard(info: &str) -> Keyboard {
+        Keyboard {
+            name: "test".to_string(),
+            dirs: Vec::new(),
+            info: json::parse(info).unwrap(),
+        }
+    }
+
+    #[test]
+    fn matrix_size() {
+        // the last row and column have no keys in the layout
+        let layouts = r#""layouts": {"LAYOUT": {"layout": [{"matrix": [0, 0]}, {"matrix": [1, 2]}]}}"#;
+        let size = |fields: &str| keyboard(&format!("{{{}, {}}}", fields, layouts)).matrix_size();
+        assert_eq!(size(r#""matrix_size": {"rows": 5, "cols": 7}"#), (5, 7));
+        assert_eq!(size(r#""matrix_pins": {"rows": ["B1", "B2", "B3"], "cols": ["C1", "C2", "C3", "C4"]}"#), (3, 4));
+        assert_eq!(size(r#""matrix_pins": {"direct": [["A1", "A2", "A3", null]]}"#), (1, 4));
+        assert_eq!(
+            size(r#""split": {"enabled": true}, "matrix_pins": {"rows": ["B1", "B2"], "cols": ["C1", "C2", "C3", "C4"]}"#),
+            (4, 4)
+        );
+        assert_eq!(size(r#""matrix_pins": {"custom": true}"#), (2, 3));
+    }
+
+    #[test]
+    fn keyboard_folder() {
+        let aliases = HashMap::from([
+            ("crkbd".to_string(), "crkbd/rev1".to_string()),
+            ("old".to_string(), "new".to_string()),
+        ]);
+        let default_folders = HashMap::from([("new", "new/rev2"), ("a", "b"), ("b", "a")]);
+        let folder = |name| {
+            super::keyboard_folder(name, &aliases, |name| {
+                default_folders.get(name).map(|f| f.to_string())
+            })
+        };
+        assert_eq!(folder("crkbd"), "crkbd/rev1");
+        assert_eq!(folder("old"), "new/rev2");
+        assert_eq!(folder("planck/rev6"), "planck/rev6");
+        assert_eq!(folder("a"), "a");
+    }
+}
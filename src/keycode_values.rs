@@ -0,0 +1,156 @@
+use crate::keycodes;
+use crate::keymap_c_parser::{Constants, Keycode};
+use crate::mods::Mods;
+
+// ranges of QMK's 16-bit keycodes, see `quantum/keycodes.h`
+const QK_MOD_TAP: u16 = 0x2000;
+const QK_LAYER_TAP: u16 = 0x4000;
+const QK_LAYER_MOD: u16 = 0x5000;
+const QK_TO: u16 = 0x5200;
+const QK_MOMENTARY: u16 = 0x5220;
+const QK_DEF_LAYER: u16 = 0x5240;
+const QK_TOGGLE_LAYER: u16 = 0x5260;
+const QK_ONE_SHOT_LAYER: u16 = 0x5280;
+const QK_ONE_SHOT_MOD: u16 = 0x52A0;
+const QK_LAYER_TAP_TOGGLE: u16 = 0x52C0;
+const QK_PERSISTENT_DEF_LAYER: u16 = 0x52E0;
+const QK_TAP_DANCE: u16 = 0x5700;
+const QK_MACRO: u16 = 0x7700;
+const QK_KB: u16 = 0x7E00;
+const QK_USER: u16 = 0x7E40;
+const QK_UNICODE: u16 = 0x8000;
+
+impl Keycode {
+    /// The keycode with this value, named through the spec where it has an entry. Layers are
+    /// named through `constants`. Values of ranges without a structure here, like unicode,
+    /// come out as `Raw`.
+    pub fn decode(code: u16, constants: &Constants) -> Self {
+        let layer = |index: u16| constants.layer_name(index as i64);
+        match code {
+            0 => return Keycode::NONE,
+            1 => return Keycode::TRANSPARENT,
+            // specs name macro keys too, but they're drawn from the macro table
+            0x7700..=0x777F => return Keycode::Macro((code - QK_MACRO) as u8),
+            _ if keycodes::table().get(code).is_some() => return Keycode::Basic(code),
+            _ => {}
+        }
+        match code {
+            0x0100..=0x1FFF => Keycode::Mods(
+                Mods::from_qmk_bits((code >> 8) as u8 & 0x1F),
+                Box::new(Self::decode(code & 0xFF, constants)),
+            ),
+            0x2000..=0x3FFF => Keycode::ModTap(
+                Mods::from_qmk_bits((code >> 8) as u8 & 0x1F),
+                Box::new(Self::decode(code & 0xFF, constants)),
+            ),
+            0x4000..=0x4FFF => Keycode::LT(
+                layer((code >> 8) & 0x0F),
+                Box::new(Self::decode(code & 0xFF, constants)),
+            ),
+            0x5000..=0x51FF => Keycode::LM(
+                layer((code >> 5) & 0x0F),
+                Mods::from_qmk_bits(code as u8 & 0x1F),
+            ),
+            0x5200..=0x52FF => {
+                let index = code & 0x1F;
+                match code & !0x1F {
+                    QK_TO => Keycode::TO(layer(index)),
+                    QK_MOMENTARY => Keycode::MO(layer(index)),
+                    QK_DEF_LAYER => Keycode::DF(layer(index)),
+                    QK_TOGGLE_LAYER => Keycode::TG(layer(index)),
+                    QK_ONE_SHOT_LAYER => Keycode::OSL(layer(index)),
+                    QK_ONE_SHOT_MOD => Keycode::OSM(Mods::from_qmk_bits(index as u8)),
+                    QK_LAYER_TAP_TOGGLE => Keycode::TT(layer(index)),
+                    _ => Keycode::PDF(layer(index)),
+                }
+            }
+            0x5700..=0x57FF => Keycode::TD(code as u8),
+            0x7E00..=0x7E3F => Keycode::Custom(format!("QK_KB_{}", code - QK_KB)),
+            0x7E40..=0x7FFF => Keycode::Custom(format!("QK_USER_{}", code - QK_USER)),
+            0x8000.. => Keycode::Raw(format!("UC({:#06x})", code - QK_UNICODE)),
+            _ => Keycode::Raw(format!("{:#06x}", code)),
+        }
+    }
+
+    /// The 16-bit value of the keycode, `None` for custom keycodes without a known value and
+    /// for combinations QMK can't express, like mixed left and right-hand mods
+    pub fn encode(&self, constants: &Constants) -> Option<u16> {
+        let layer = |name: &str, max: i64| {
+            constants
+                .layer_index(name)
+                .filter(|index| (0..=max).contains(index))
+                .map(|index| index as u16)
+        };
+        let mods = |mods: &Mods| mods.to_qmk_bits().map(u16::from);
+        // mod and layer taps only have room for a basic keycode
+        let basic = |key: &Keycode| key.encode(constants).filter(|code| *code <= 0xFF);
+        Some(match self {
+            Self::NONE => 0,
+            Self::TRANSPARENT => 1,
+            Self::Basic(code) => *code,
+            Self::Custom(name) => {
+                if let Some(spec) = keycodes::table().lookup(name) {
+                    spec.code
+                } else if let Some(n) = name.strip_prefix("QK_KB_") {
+                    QK_KB + n.parse::<u16>().ok().filter(|n| *n < 0x40)?
+                } else {
+                    QK_USER + name.strip_prefix("QK_USER_")?.parse::<u16>().ok().filter(|n| *n < 0x1C0)?
+                }
+            }
+            Self::MO(name) => QK_MOMENTARY | layer(name, 0x1F)?,
+            Self::TG(name) => QK_TOGGLE_LAYER | layer(name, 0x1F)?,
+            Self::TO(name) => QK_TO | layer(name, 0x1F)?,
+            Self::TT(name) => QK_LAYER_TAP_TOGGLE | layer(name, 0x1F)?,
+            Self::DF(name) => QK_DEF_LAYER | layer(name, 0x1F)?,
+            Self::PDF(name) => QK_PERSISTENT_DEF_LAYER | layer(name, 0x1F)?,
+            Self::OSL(name) => QK_ONE_SHOT_LAYER | layer(name, 0x1F)?,
+            Self::LM(name, mods_) => QK_LAYER_MOD | (layer(name, 0x0F)? << 5) | mods(mods_)?,
+            Self::LT(name, tap) => QK_LAYER_TAP | (layer(name, 0x0F)? << 8) | basic(tap)?,
+            // the mods make up the high byte, so the range starts at QK_MODS with LCTL
+            Self::Mods(mods_, key) => (mods(mods_).filter(|m| *m != 0)? << 8) | basic(key)?,
+            Self::ModTap(mods_, tap) => QK_MOD_TAP | (mods(mods_)? << 8) | basic(tap)?,
+            Self::OSM(mods_) => QK_ONE_SHOT_MOD | mods(mods_)?,
+            Self::TD(index) => QK_TAP_DANCE | *index as u16,
+            Self::Macro(index) if *index < 0x80 => QK_MACRO | *index as u16,
+            Self::Macro(_) => return None,
+            // whatever `decode` couldn't give a structure
+            Self::Raw(text) => match text.strip_prefix("UC(").and_then(|t| t.strip_suffix(')')) {
+                Some(code) => QK_UNICODE | parse_hex(code).filter(|code| *code < 0x8000)?,
+                None => parse_hex(text)?,
+            },
+        })
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u16> {
+    u16::from_str_radix(text.strip_prefix("0x")?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let constants = Constants::default();
+        let codes = [
+            0x0000, 0x0001, 0x0004, 0x002C, // basic
+            0x0104, 0x0604, 0x1204, // mods
+            0x2204, 0x3104, // mod taps
+            0x412C, // layer tap
+            0x5023, // layer mod
+            0x5202, 0x5221, 0x5243, 0x5264, 0x5285, 0x52A2, 0x52C6, 0x52E7, // layers and OSM
+            0x5703, // tap dance
+            0x7703, // macro
+            0x7E01, 0x7E41, // keyboard and user
+            0x8123, // unicode
+        ];
+        for code in codes {
+            let text = Keycode::decode(code, &constants).to_string();
+            let mut warnings = Vec::new();
+            let keycode = Keycode::parse_text(&text, &constants, &mut warnings);
+            assert!(warnings.is_empty(), "{:#06x} {}: {:?}", code, text, warnings);
+            assert_eq!(keycode.encode(&constants), Some(code), "{:#06x} {}", code, text);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use json::JsonValue;
+
+use crate::hjson;
+use crate::mods::Mods;
+
+/// One entry of QMK's keycode spec
+#[derive(Debug, Clone)]
+pub struct KeycodeSpec {
+    pub code: u16,
+    pub key: String,
+    pub label: Option<String>,
+    pub aliases: Vec<String>,
+}
+
+impl KeycodeSpec {
+    fn from_entry(code: u16, entry: &JsonValue) -> Option<Self> {
+        Some(KeycodeSpec {
+            code,
+            key: entry["key"].as_str()?.to_string(),
+            label: entry["label"]
+                .as_str()
+                .filter(|l| !l.is_empty())
+                .map(str::to_string),
+            aliases: entry["aliases"]
+                .members()
+                .filter_map(|a| a.as_str().map(str::to_string))
+                .collect(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct KeycodeTable {
+    by_code: HashMap<u16, KeycodeSpec>,
+    by_name: HashMap<String, u16>,
+    /// Aliases for values that are shown under another name, e.g. `DE_AT`
+    unshown: HashMap<String, KeycodeSpec>,
+}
+
+static TABLE: OnceLock<KeycodeTable> = OnceLock::new();
+
+const BUILTIN_SPEC: &str = include_str!("keycodes_builtin.hjson");
+
+/// Loads the keycode spec from `data/constants/keycodes/` of a qmk_firmware checkout,
+/// keeping the built-in table if that doesn't work out. Only the first call has an effect.
+pub fn init(qmk_home: &Path) {
+    let table = match load_spec_dir(&qmk_home.join("data/constants/keycodes")) {
+        Ok(table) => table,
+        Err(e) => {
+            eprintln!("warning: {}, using the built-in keycode table", e);
+            builtin()
+        }
+    };
+    let _ = TABLE.set(table);
+}
+
+pub fn table() -> &'static KeycodeTable {
+    TABLE.get_or_init(builtin)
+}
+
+fn builtin() -> KeycodeTable {
+    let spec = hjson::parse(BUILTIN_SPEC).expect("built-in keycode spec is valid");
+    KeycodeTable::from_spec(&spec)
+}
+
+/// Loads `dir` and its `extras/`, which older checkouts don't have
+fn load_spec_dir(dir: &Path) -> Result<KeycodeTable, String> {
+    let files = read_spec_files(dir)?;
+    if files.is_empty() {
+        return Err(format!("no keycode spec files in {}", dir.display()));
+    }
+    let extras = read_spec_files(&dir.join("extras")).unwrap_or_default();
+    Ok(table_from_files(files, extras))
+}
+
+/// Every `keycodes_*.hjson` in `dir`, with its file name
+fn read_spec_files(dir: &Path) -> Result<Vec<(String, JsonValue)>, String> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("can't read {}: {}", dir.display(), e))?;
+    let mut files = Vec::new();
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !(name.starts_with("keycodes_") && name.ends_with(".hjson")) {
+            continue;
+        }
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+        let spec =
+            hjson::parse(&text).map_err(|e| format!("can't parse {}: {}", path.display(), e))?;
+        files.push((name.to_string(), spec));
+    }
+    Ok(files)
+}
+
+/// Merges every `keycodes_<version>[_<group>].hjson` in version order, like the qmk cli.
+/// Later versions rename keycodes and drop others with `"!delete!"`. The language aliases of
+/// `extras`, `keycodes_<language>_<version>.hjson`, go on top.
+fn table_from_files(files: Vec<(String, JsonValue)>, extras: Vec<(String, JsonValue)>) -> KeycodeTable {
+    let mut files = files
+        .into_iter()
+        .filter_map(|(name, spec)| {
+            let rest = name.strip_prefix("keycodes_")?.strip_suffix(".hjson")?;
+            let version = rest
+                .split('_')
+                .next()?
+                .split('.')
+                .map(|n| n.parse::<u32>().ok())
+                .collect::<Option<Vec<_>>>()?;
+            Some((version, name, spec))
+        })
+        .collect::<Vec<_>>();
+    files.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+    let mut spec = JsonValue::new_object();
+    for (_, _, fragment) in files {
+        merge_spec(&mut spec, fragment);
+    }
+    let mut table = KeycodeTable::from_spec(&spec);
+
+    let mut extras = extras
+        .into_iter()
+        .filter_map(|(name, spec)| {
+            let rest = name.strip_prefix("keycodes_")?.strip_suffix(".hjson")?;
+            let (language, _) = rest.rsplit_once('_')?;
+            Some((language != "us", name, spec))
+        })
+        .collect::<Vec<_>>();
+    // US first, its names are the ones shown for the values
+    extras.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+    for (other_language, _, fragment) in extras {
+        table.add_aliases(&fragment["aliases"], !other_language);
+    }
+    table
+}
+
+fn merge_spec(spec: &mut JsonValue, fragment: JsonValue) {
+    for (section, entries) in fragment.entries() {
+        if !entries.is_object() {
+            spec[section] = entries.clone();
+            continue;
+        }
+        for (key, value) in entries.entries() {
+            if value.as_str() == Some("!delete!") {
+                spec[section].remove(key);
+            } else if value.is_object() && spec[section][key].is_object() {
+                for (field, v) in value.entries() {
+                    spec[section][key][field] = v.clone();
+                }
+            } else {
+                spec[section][key] = value.clone();
+            }
+        }
+    }
+}
+
+impl KeycodeTable {
+    fn from_spec(spec: &JsonValue) -> Self {
+        let mut table = KeycodeTable::default();
+        for (code, entry) in spec["keycodes"].entries() {
+            let Some(code) = code
+                .strip_prefix("0x")
+                .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+            else {
+                continue;
+            };
+            let Some(spec) = KeycodeSpec::from_entry(code, entry) else {
+                continue;
+            };
+            table.add_names(&spec);
+            table.by_code.insert(code, spec);
+        }
+        table.add_aliases(&spec["aliases"], true);
+        table
+    }
+
+    /// Adds names for keycode expressions, `{ "S(KC_1)": { key: KC_EXCLAIM, aliases: [...] } }`.
+    /// With `shown` the names are also the ones given to their values, unless those have one.
+    fn add_aliases(&mut self, aliases: &JsonValue, shown: bool) {
+        for (expr, entry) in aliases.entries() {
+            let Some(spec) = self.resolve(expr).and_then(|code| KeycodeSpec::from_entry(code, entry))
+            else {
+                continue;
+            };
+            self.add_names(&spec);
+            if shown && !self.by_code.contains_key(&spec.code) {
+                self.by_code.insert(spec.code, spec);
+            } else {
+                for name in std::iter::once(&spec.key).chain(&spec.aliases) {
+                    self.unshown.insert(name.clone(), spec.clone());
+                }
+            }
+        }
+    }
+
+    fn add_names(&mut self, spec: &KeycodeSpec) {
+        for name in std::iter::once(&spec.key).chain(&spec.aliases) {
+            self.by_name.insert(name.clone(), spec.code);
+        }
+    }
+
+    /// The value of a name, possibly wrapped in modifiers like `S(ALGR(KC_2))`
+    fn resolve(&self, expr: &str) -> Option<u16> {
+        let mut mods = Mods(0);
+        let mut expr = expr.trim();
+        while let Some((wrapper, inner)) = expr.strip_suffix(')').and_then(|e| e.split_once('(')) {
+            mods = mods.union(Mods::from_wrapper_name(wrapper.trim())?);
+            expr = inner.trim();
+        }
+        let code = *self.by_name.get(expr)?;
+        if mods == Mods(0) {
+            return Some(code);
+        }
+        let bits = u16::from(mods.to_qmk_bits()?);
+        (code <= 0xFF).then_some(bits << 8 | code)
+    }
+
+    pub fn get(&self, code: u16) -> Option<&KeycodeSpec> {
+        self.by_code.get(&code)
+    }
+
+    /// Looks a keycode up by its name or any alias
+    pub fn lookup(&self, name: &str) -> Option<&KeycodeSpec> {
+        self.by_name
+            .get(name)
+            .and_then(|code| self.by_code.get(code))
+            .or_else(|| self.unshown.get(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_groups() {
+        let table = builtin();
+        for (name, code) in [
+            ("KC_EXLM", 0x021E),
+            ("KC_TILD", 0x0235),
+            ("KC_QUES", 0x0238),
+            ("RGB_MOD", 0x7821),
+            ("BL_TOGG", 0x7802),
+            ("AU_TOGG", 0x7482),
+            ("QK_MACRO_31", 0x771F),
+            ("QK_KB_0", 0x7E00),
+            ("QK_USER_1", 0x7E41),
+        ] {
+            assert_eq!(table.lookup(name).map(|spec| spec.code), Some(code), "{}", name);
+        }
+        assert_eq!(table.get(0x021E).unwrap().label.as_deref(), Some("!"));
+    }
+
+    #[test]
+    fn extras() {
+        let file = |name: &str, source: &str| (name.to_string(), hjson::parse(source).unwrap());
+        let files = vec![file(
+            "keycodes_0.0.1_basic.hjson",
+            r#"{ keycodes: {
+                "0x001E": { group: basic, key: KC_1, aliases: [] }
+                "0x001F": { group: basic, key: KC_2, aliases: [] }
+                "0x0014": { group: basic, key: KC_Q, aliases: [] }
+            } }"#,
+        )];
+        let extras = vec![
+            file(
+                "keycodes_german_0.0.1.hjson",
+                r#"{ aliases: {
+                    "KC_2": { key: DE_2, label: "2" }
+                    "S(DE_2)": { key: DE_DQUO, label: "\"" }
+                    "S(KC_1)": { key: DE_EXLM, label: "!" }
+                    "ALGR(KC_Q)": { key: DE_AT, label: "@" }
+                } }"#,
+            ),
+            file(
+                "keycodes_us_0.0.1.hjson",
+                r#"{ aliases: { "S(KC_1)": { key: KC_EXCLAIM, label: "!", aliases: [KC_EXLM] } } }"#,
+            ),
+        ];
+        let table = table_from_files(files, extras);
+
+        let code = |name: &str| table.lookup(name).map(|spec| spec.code);
+        assert_eq!(code("KC_EXLM"), Some(0x021E));
+        assert_eq!(code("DE_EXLM"), Some(0x021E));
+        assert_eq!(code("DE_DQUO"), Some(0x021F));
+        assert_eq!(code("DE_AT"), Some(0x1414));
+        // only the US names are shown
+        assert_eq!(table.get(0x021E).unwrap().key, "KC_EXCLAIM");
+        assert_eq!(table.get(0x001F).unwrap().key, "KC_2");
+        assert!(table.get(0x1414).is_none());
+    }
+}
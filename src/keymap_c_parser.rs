@@ -1,518 +1,869 @@
-use ab_glyph::{FontArc, PxScale};
-use tiny_skia::{Color, Pixmap};
-
-use crate::draw_text;
-
-
-pub fn parse_c_source(path: &str) -> Vec<Layer> {
-    const START_KEYMAP: &str = "const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {";
-    let keymap_string = std::fs::read_to_string(path).expect("Failed to read keymap.c");
-    //remove new lines
-    let keymap_string = keymap_string.replace("\n", " ");
-    let keymap_start = keymap_string
-        .find(START_KEYMAP)
-        .expect("Failed to find keymaps");
-    let keymap_string = &keymap_string[(keymap_start + START_KEYMAP.len())..];
-    let keymap_end = keymap_string
-        .find("};")
-        .expect("Failed to find end of keymaps");
-    let keymap_string = &keymap_string[..keymap_end];
-
-    //remove /* */ comments
-    let mut keymap_string = keymap_string.to_string();
-    while let Some(start) = keymap_string.find("/*") {
-        if let Some(end) = keymap_string[start..].find("*/") {
-            keymap_string.replace_range(start..(start + end + 2), "");
-        } else {
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use ab_glyph::PxScale;
+use tiny_skia::{Color, Pixmap, Rect};
+
+use crate::c_lexer::{Token, TokenKind, tokenize};
+use crate::c_preprocessor::{Preprocessor, parse_int};
+use crate::diagnostic::{Diagnostic, Severity, Span};
+use crate::keycodes;
+use crate::legends::{self, Legend};
+use crate::mods::Mods;
+use crate::text::{GlyphCache, draw_text, draw_text_fitted, measure_text};
+
+/// QMK's layer state is a 32-bit mask
+const MAX_LAYERS: i64 = 32;
+
+/// Parses the `keymaps` array of a keymap.c. Returns the layout macro it calls, which has to be
+/// one of `layouts`, together with the layers, or `None` if it calls none of them.
+pub fn parse_c_source(
+    path: &Path,
+    layouts: &[String],
+    include_dirs: &[PathBuf],
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<Option<(String, Vec<Layer>)>, Diagnostic> {
+    let mut preprocessor = Preprocessor::new(include_dirs.to_vec());
+    // a qmk build sees the config.h of every keyboard folder and of the keymap, most specific last
+    for dir in include_dirs.iter().rev() {
+        preprocessor.load_defines(&dir.join("config.h"));
+    }
+    let tokens = preprocessor.process_file(path)?;
+    parse_tokens(&tokens, path, layouts, warnings)
+}
+
+/// `parse_c_source` on the preprocessed tokens of the keymap.c at `path`
+fn parse_tokens(
+    tokens: &[Token],
+    path: &Path,
+    layouts: &[String],
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<Option<(String, Vec<Layer>)>, Diagnostic> {
+    // const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = { ... };
+    let start = tokens
+        .windows(2)
+        .position(|w| w[0].ident() == Some("keymaps") && w[1].is_punct("["))
+        .ok_or_else(|| format!("no `keymaps` array in {}", path.display()))?;
+    let open = tokens[start..]
+        .iter()
+        .position(|t| t.is_punct("{"))
+        .map(|p| start + p + 1)
+        .ok_or_else(|| {
+            Diagnostic::error("`keymaps` has no initializer", Span::of(&tokens[start..start + 1]))
+        })?;
+
+    let (values, enumerators, enums) = parse_enums(tokens);
+    let mut constants = Constants {
+        values,
+        enumerators,
+        ..Default::default()
+    };
+
+    // designators first, keys can refer to layers defined further down
+    let mut entries = Vec::new();
+    let mut layout_name = None;
+    let mut next_index = 0;
+    let mut pos = open;
+    while pos < tokens.len() {
+        let token = &tokens[pos];
+        if token.is_punct("}") {
             break;
         }
+        if token.is_punct(",") {
+            pos += 1;
+            continue;
+        }
+
+        let mut index = next_index;
+        let mut name = None;
+        let mut index_tokens = &tokens[pos..pos + 1];
+        if token.is_punct("[") {
+            let (designator, end) = balanced(tokens, pos)?;
+            index_tokens = designator;
+            let expr = Expr::parse(designator)?;
+            index = constants.eval(&expr).ok_or_else(|| {
+                Diagnostic::error(
+                    format!("can't resolve layer index `{}`", expr),
+                    Span::of(designator),
+                )
+                .with_note("layer indices have to be numbers, enumerators or #defines")
+            })?;
+            name = match (&expr, designator) {
+                (Expr::Ident(ident), _) => Some(ident.clone()),
+                // a `#define`d layer constant, already replaced by its value
+                (_, [single]) => single.origin.clone(),
+                _ => None,
+            };
+            pos = end;
+            if tokens.get(pos).is_some_and(|t| t.is_punct("=")) {
+                pos += 1;
+            }
+        }
+        if !(0..MAX_LAYERS).contains(&index) {
+            return Err(Diagnostic::error(
+                format!("layer index {} is out of range", index),
+                Span::of(index_tokens),
+            )
+            .with_note(format!("QMK has layers 0 to {}", MAX_LAYERS - 1)));
+        }
+        next_index = index + 1;
+
+        let call = tokens
+            .get(pos)
+            .and_then(|t| t.ident())
+            .filter(|_| tokens.get(pos + 1).is_some_and(|t| t.is_punct("(")));
+        // every layer has to go through the same layout macro as the first one
+        let expected = match &layout_name {
+            Some(name) => std::slice::from_ref(name),
+            None => layouts,
+        };
+        let Some(call) = call.filter(|c| expected.iter().any(|l| l == c)) else {
+            // the first layer not calling any candidate means the keymap uses another layout
+            if layout_name.is_none() {
+                return Ok(None);
+            }
+            let found = &tokens[pos.min(tokens.len() - 1)..][..1];
+            return Err(Diagnostic::error(
+                format!(
+                    "expected `{}(` for layer {}, found `{}`",
+                    expected[0],
+                    index,
+                    found[0].text()
+                ),
+                Span::of(found),
+            ));
+        };
+        layout_name.get_or_insert_with(|| call.to_string());
+        let (args, end) = balanced(tokens, pos + 1)?;
+        pos = end;
+
+        if let Some(name) = &name {
+            constants.layer_names.insert(index, name.clone());
+        }
+        entries.push((index, args));
+    }
+    // layers without a designator are named after the enumerator with their index
+    if let Some(layer_enum) = layer_enum(&enums, tokens) {
+        for name in &layer_enum.names {
+            if let Some(&index) = constants.values.get(name) {
+                constants.layer_names.entry(index).or_insert_with(|| name.clone());
+            }
+        }
     }
 
-    //find all instances of [ ... ]
-    let mut layer_names = Vec::new();
-    let mut rest = keymap_string.as_str();
-    while let Some(start) = rest.find('[') {
-        if let Some(end) = rest[start..].find(']') {
-            let layer = &rest[(start + 1)..(start + end)];
-            layer_names.push(layer.trim().to_string());
-            rest = &rest[(start + end + 1)..];
-        } else {
-            break;
+    let layer_count = entries.iter().map(|(index, _)| index + 1).max().unwrap_or(0);
+    let mut layers = (0..layer_count)
+        .map(|index| Layer {
+            name: constants.layer_name(index),
+            keys: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+    let mut key_count = 0;
+    for (index, args) in entries {
+        let keys = split_top_level(args)
+            .iter()
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| parse_key(arg, &constants, warnings))
+            .collect::<Vec<_>>();
+        key_count = key_count.max(keys.len());
+        layers[index as usize].keys = keys;
+    }
+    // layers the keymap skips fall through to the ones below
+    for layer in &mut layers {
+        if layer.keys.is_empty() {
+            layer.keys = (0..key_count).map(|_| Keycode::TRANSPARENT).collect();
         }
     }
+    Ok(layout_name.map(|name| (name, layers)))
+}
 
-    let mut layer_keys = Vec::new();
-
-    let mut out_rest = keymap_string.as_str();
-    while let Some(start) = out_rest.find("LAYOUT_40_macro(") {
-        rest = &out_rest[(start + "LAYOUT_40_macro(".len())..];
-        let mut curr_nesting = 1;
-        loop {
-            let next_open = rest.find('(');
-            let next_close = rest.find(')');
-            match (next_open, next_close) {
-                (Some(o), Some(c)) => {
-                    if o < c {
-                        curr_nesting += 1;
-                        rest = &rest[(o + 1)..];
-                    } else {
-                        curr_nesting -= 1;
-                        if curr_nesting == 0 {
-                            let layer = out_rest[(start + "LAYOUT_40_macro(".len())
-                                ..(out_rest.len() - rest.len() + c)]
-                                .trim();
-                            layer_keys.push(layer.to_string());
-
-                            out_rest = &rest[(c + 1)..];
-                            break;
-                        } else {
-                            rest = &rest[(c + 1)..];
-                        }
-                    }
-                }
-                (Some(_), None) => {
-                    panic!("Unmatched parentheses");
+/// One argument of a layout macro, falling back to its text when it isn't a known keycode
+fn parse_key(tokens: &[Token], constants: &Constants, warnings: &mut Vec<Diagnostic>) -> Keycode {
+    let expr = match Expr::parse(tokens) {
+        Ok(expr) => expr,
+        Err(e) => {
+            warnings.push(Diagnostic {
+                severity: Severity::Warning,
+                ..e
+            });
+            let text = tokens.iter().map(Token::text).collect::<Vec<_>>().join(" ");
+            return Keycode::Raw(text);
+        }
+    };
+    let mut messages = Vec::new();
+    let keycode = Keycode::parse(&expr, constants, &mut messages);
+    warnings.extend(
+        messages
+            .into_iter()
+            .map(|message| Diagnostic::warning(message, Span::of(tokens))),
+    );
+    keycode
+}
+
+/// The tag and enumerator names of one enum
+struct Enum {
+    tag: Option<String>,
+    names: Vec<String>,
+}
+
+/// Values of every enumerator in the file, e.g. `enum layers { BASE, NAV = 3, SYM }`.
+/// Enumerators following one that can't be evaluated (`= SAFE_RANGE`) are left out of the
+/// values, the second set has every enumerator name and the list every enum in file order.
+fn parse_enums(tokens: &[Token]) -> (HashMap<String, i64>, HashSet<String>, Vec<Enum>) {
+    let mut values = HashMap::new();
+    let mut names = HashSet::new();
+    let mut enums = Vec::new();
+    let mut pos = 0;
+    while pos < tokens.len() {
+        if tokens[pos].ident() != Some("enum") {
+            pos += 1;
+            continue;
+        }
+        pos += 1;
+        let tag = tokens.get(pos).and_then(Token::ident).map(str::to_string);
+        if tag.is_some() {
+            pos += 1;
+        }
+        if !tokens.get(pos).is_some_and(|t| t.is_punct("{")) {
+            continue;
+        }
+        let Ok((body, end)) = balanced(tokens, pos) else {
+            break;
+        };
+        pos = end;
+
+        let mut next = Some(0);
+        let mut members = Vec::new();
+        for entry in split_top_level(body) {
+            let Some(name) = entry.first().and_then(Token::ident) else {
+                continue;
+            };
+            names.insert(name.to_string());
+            members.push(name.to_string());
+            let value = match entry.get(1) {
+                Some(eq) if eq.is_punct("=") => {
+                    let constants = Constants {
+                        values: values.clone(),
+                        ..Default::default()
+                    };
+                    Expr::parse(&entry[2..]).ok().and_then(|e| constants.eval(&e))
                 }
-                (None, Some(c)) => {
-                    curr_nesting -= 1;
-                    if curr_nesting == 0 {
-                        let layer = out_rest
-                            [(start + "LAYOUT_40_macro(".len())..(out_rest.len() - rest.len() + c)]
-                            .trim();
-                        layer_keys.push(layer.to_string());
-                        out_rest = &rest[(c + 1)..];
-                        break;
-                    } else {
-                        rest = &rest[(c + 1)..];
-                    }
+                _ => next,
+            };
+            if let Some(value) = value {
+                values.insert(name.to_string(), value);
+            }
+            next = value.and_then(|v| v.checked_add(1));
+        }
+        enums.push(Enum { tag, names: members });
+    }
+    (values, names, enums)
+}
+
+/// The enum listing the layers: the first one tagged like `layers` or `layer_names`, else the
+/// first one with a member used as the layer of `MO(..)`, `LT(..)` and the like
+fn layer_enum<'a>(enums: &'a [Enum], tokens: &[Token]) -> Option<&'a Enum> {
+    const LAYER_KEYCODES: [&str; 9] = ["MO", "TG", "TO", "TT", "DF", "PDF", "OSL", "LM", "LT"];
+    let tagged = enums
+        .iter()
+        .find(|e| e.tag.as_deref().is_some_and(|tag| tag.to_lowercase().contains("layer")));
+    tagged.or_else(|| {
+        let layer_args = tokens
+            .windows(3)
+            .filter(|w| w[0].ident().is_some_and(|f| LAYER_KEYCODES.contains(&f)))
+            .filter(|w| w[1].is_punct("("))
+            .filter_map(|w| w[2].ident())
+            .collect::<HashSet<_>>();
+        enums
+            .iter()
+            .find(|e| e.names.iter().any(|name| layer_args.contains(name.as_str())))
+    })
+}
+
+/// Numbers the keymap gives names to, used to resolve layer arguments
+#[derive(Default)]
+pub struct Constants {
+    pub values: HashMap<String, i64>,
+    pub layer_names: HashMap<i64, String>,
+    /// Enumerator names, including custom keycodes without a known value
+    pub enumerators: HashSet<String>,
+}
+
+impl Constants {
+    pub fn eval(&self, expr: &Expr) -> Option<i64> {
+        match expr {
+            Expr::Number(n) => Some(*n),
+            Expr::Ident(name) => self.values.get(name).copied(),
+            Expr::Call(..) => None,
+            Expr::Binary(lhs, op, rhs) => {
+                let (lhs, rhs) = (self.eval(lhs)?, self.eval(rhs)?);
+                match *op {
+                    "|" => Some(lhs | rhs),
+                    "+" => lhs.checked_add(rhs),
+                    "-" => lhs.checked_sub(rhs),
+                    "&" => Some(lhs & rhs),
+                    "<<" => lhs.checked_shl(u32::try_from(rhs).ok()?),
+                    _ => lhs.checked_shr(u32::try_from(rhs).ok()?),
                 }
-                (None, None) => panic!("Unmatched parentheses"),
             }
         }
     }
 
-    let parsed_layers = layer_keys.iter().map(|layer| {
-        let mut keys = layer.split(',').map(|k| k.trim());
-        let mut parsed_arr = Vec::new();
-        while let Some(key) = keys.next() {
-            if key.starts_with("MT(") || key.starts_with("LT(") {
-                let second_part = keys.next().expect("Unmatched MT( or LT(");
-                let parsed_key = format!("{},{}", key, second_part);
-                parsed_arr.push(Keycode::parse_str(&parsed_key));
-            } else {
-                parsed_arr.push(Keycode::parse_str(key));
+    pub fn layer_name(&self, index: i64) -> String {
+        self.layer_names
+            .get(&index)
+            .cloned()
+            .unwrap_or_else(|| index.to_string())
+    }
+
+    /// The index of a layer named by `layer_name` or `layer_ref`
+    pub fn layer_index(&self, name: &str) -> Option<i64> {
+        self.layer_names
+            .iter()
+            .find(|(_, layer)| *layer == name)
+            .map(|(index, _)| *index)
+            .or_else(|| self.values.get(name).copied())
+            .or_else(|| name.parse().ok())
+    }
+
+    /// The name of the layer a layer argument like `_NAV` or `2` points at
+    pub fn layer_ref(&self, expr: &Expr) -> String {
+        self.eval(expr)
+            .and_then(|index| self.layer_names.get(&index).cloned())
+            .unwrap_or_else(|| expr.to_string())
+    }
+}
+
+/// Returns the tokens between the bracket at `open` and its partner, and the position after it
+fn balanced(tokens: &[Token], open: usize) -> Result<(&[Token], usize), Diagnostic> {
+    const PAIRS: [(&str, &str); 3] = [("(", ")"), ("[", "]"), ("{", "}")];
+    // the closer each open bracket is waiting for, and where it is
+    let mut openers: Vec<(&str, usize)> = Vec::new();
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        if let Some((_, closer)) = PAIRS.iter().find(|(opener, _)| token.is_punct(opener)) {
+            openers.push((closer, i));
+        } else if PAIRS.iter().any(|(_, closer)| token.is_punct(closer)) {
+            let Some((closer, at)) = openers.pop() else {
+                break;
+            };
+            if !token.is_punct(closer) {
+                return Err(unmatched(tokens, at));
+            }
+            if openers.is_empty() {
+                return Ok((&tokens[open + 1..i], i + 1));
             }
         }
-        parsed_arr
+    }
+    Err(unmatched(tokens, openers.last().map_or(open, |(_, at)| *at)))
+}
 
-    }).collect::<Vec<_>>();
+/// An error for the bracket at `at`, together with the macro name it may follow
+fn unmatched(tokens: &[Token], at: usize) -> Diagnostic {
+    let bracket = &tokens[at];
+    let start = match at.checked_sub(1).map(|before| &tokens[before]) {
+        Some(name) if bracket.is_punct("(") && name.ident().is_some() && name.line == bracket.line => at - 1,
+        _ => at,
+    };
+    Diagnostic::error(
+        format!("unmatched `{}`", bracket.text()),
+        Span::of(&tokens[start..=at]),
+    )
+}
+
+/// Splits macro arguments on commas that aren't nested in parentheses
+fn split_top_level(tokens: &[Token]) -> Vec<&[Token]> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if token.is_punct("(") {
+            depth += 1;
+        } else if token.is_punct(")") {
+            depth -= 1;
+        } else if token.is_punct(",") && depth == 0 {
+            parts.push(&tokens[start..i]);
+            start = i + 1;
+        }
+    }
+    parts.push(&tokens[start..]);
+    parts
+}
 
-    layer_names.into_iter().zip(parsed_layers).map(|(name, keys)| {
-        Layer { name, keys }
-    }).collect::<Vec<_>>()
+/// A keycode expression such as `KC_A`, `LT(_NAV, KC_SPC)` or `MOD_LCTL | MOD_LSFT`
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Ident(String),
+    Number(i64),
+    Call(String, Vec<Expr>),
+    Binary(Box<Expr>, &'static str, Box<Expr>),
 }
 
+impl Expr {
+    pub fn parse(tokens: &[Token]) -> Result<Self, Diagnostic> {
+        let mut pos = 0;
+        let expr = Self::parse_binary(tokens, &mut pos)?;
+        if pos < tokens.len() {
+            return Err(unexpected(&tokens[pos..pos + 1]));
+        }
+        Ok(expr)
+    }
+
+    // keycode expressions only ever combine masks, so operators just fold left
+    fn parse_binary(tokens: &[Token], pos: &mut usize) -> Result<Self, Diagnostic> {
+        let mut lhs = Self::parse_primary(tokens, pos)?;
+        while let Some(TokenKind::Punct(op)) = tokens.get(*pos).map(|t| &t.kind) {
+            if !matches!(*op, "|" | "+" | "-" | "&" | "<<" | ">>") {
+                break;
+            }
+            *pos += 1;
+            let rhs = Self::parse_primary(tokens, pos)?;
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_primary(tokens: &[Token], pos: &mut usize) -> Result<Self, Diagnostic> {
+        let Some(token) = tokens.get(*pos) else {
+            // point just past whatever came before
+            let span = Span::of(&tokens[tokens.len().saturating_sub(1)..]).map(|span| Span {
+                col: span.col + span.len,
+                len: 1,
+                ..span
+            });
+            return Err(Diagnostic::error("expected a keycode", span));
+        };
+        *pos += 1;
+        Ok(match &token.kind {
+            TokenKind::Number(n) => Expr::Number(parse_int(n).ok_or_else(|| {
+                Diagnostic::error(format!("invalid number `{}`", n), Span::of(&tokens[*pos - 1..*pos]))
+            })?),
+            TokenKind::Punct("(") => {
+                let inner = Self::parse_binary(tokens, pos)?;
+                if tokens.get(*pos).is_some_and(|t| t.is_punct(")")) {
+                    *pos += 1;
+                }
+                inner
+            }
+            TokenKind::Ident(name) if tokens.get(*pos).is_some_and(|t| t.is_punct("(")) => {
+                let (args, end) = balanced(tokens, *pos)?;
+                *pos = end;
+                let args = if args.is_empty() {
+                    Vec::new()
+                } else {
+                    split_top_level(args)
+                        .into_iter()
+                        .map(Expr::parse)
+                        .collect::<Result<_, _>>()?
+                };
+                Expr::Call(name.clone(), args)
+            }
+            TokenKind::Ident(name) => Expr::Ident(name.clone()),
+            _ => return Err(unexpected(&tokens[*pos - 1..*pos])),
+        })
+    }
+}
+
+fn unexpected(token: &[Token]) -> Diagnostic {
+    Diagnostic::error(
+        format!("unexpected `{}` in keycode", token[0].text()),
+        Span::of(token),
+    )
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Ident(name) => write!(f, "{}", name),
+            Expr::Number(n) => write!(f, "{}", n),
+            Expr::Call(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Binary(lhs, op, rhs) => write!(f, "{} {} {}", lhs, op, rhs),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct Layer {
     pub name: String,
     pub keys: Vec<Keycode>,
 }
 
-#[allow(non_camel_case_types)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum Keycode {
     NONE,
     TRANSPARENT,
 
-    KC_A,
-    KC_B,
-    KC_C,
-    KC_D,
-    KC_E,
-    KC_F,
-    KC_G,
-    KC_H,
-    KC_I,
-    KC_J,
-    KC_K,
-    KC_L,
-    KC_M,
-    KC_N,
-    KC_O,
-    KC_P,
-    KC_Q,
-    KC_R,
-    KC_S,
-    KC_T,
-    KC_U,
-    KC_V,
-    KC_W,
-    KC_X,
-    KC_Y,
-    KC_Z,
-
-    KC_0,
-    KC_1,
-    KC_2,
-    KC_3,
-    KC_4,
-    KC_5,
-    KC_6,
-    KC_7,
-    KC_8,
-    KC_9,
-
-    KC_F1,
-    KC_F2,
-    KC_F3,
-    KC_F4,
-    KC_F5,
-    KC_F6,
-    KC_F7,
-    KC_F8,
-    KC_F9,
-    KC_F10,
-
-    KC_KB_VOLUME_UP,
-    KC_KB_VOLUME_DOWN,
-    KC_KB_MUTE,
-    KC_MEDIA_PLAY,
-    KC_MEDIA_PREV,
-    KC_MEDIA_NEXT,
-
-    KC_MINUS,
-    KC_GRV,
-    KC_QUOT,
-    KC_BSLS,
-    KC_LBRC,
-    KC_RBRC,
-    KC_EQUAL,
-    KC_COMM,
-    KC_DOT,
-    KC_SLASH,
-    KC_SCLN,
-
-    MOD_LCTL,
-    KC_BSPC,
-    KC_LGUI,
-    KC_SPACE,
-    MOD_LALT,
-    KC_TAB,
-    KC_PGUP,
-    KC_PGDN,
-    KC_HOME,
-    KC_END,
-    KC_ENT,
-    KC_ESC,
-    KC_PSCR,
-
-    MO(String), // layer
-    MT(Box<Keycode>, Box<Keycode>), //hold/tap
+    Basic(u16), // any keycode from the spec, by its 16-bit value
+    Custom(String), // a keymap's own keycode, e.g. from an enum starting at SAFE_RANGE
+
+    MO(String), // momentary layer
+    TG(String), // toggle layer
+    TO(String), // turn on layer, turning off the others
+    TT(String), // momentary on hold, toggle on repeated taps
+    DF(String), // set default layer
+    PDF(String), // set default layer and store it in EEPROM
+    OSL(String), // one-shot layer
+    LM(String, Mods), // momentary layer with modifiers
     LT(String, Box<Keycode>), // layer, keycode
-    S(Box<Keycode>), // shift
+    Mods(Mods, Box<Keycode>), // keycode sent with modifiers held, e.g. LCTL(kc)
+    ModTap(Mods, Box<Keycode>), // modifiers on hold, keycode on tap
+    OSM(Mods), // one-shot modifiers
+    TD(u8), // tap dance, by its index in the tap dance table
+    Macro(u8), // dynamic macro, by its index in the macro table
+
+    Raw(String), // source text of a keycode that isn't understood, shown as it is
 }
 
 impl Keycode {
-    fn parse_str(key: &str) -> Self {
-        match key {
-            "KC_NO" => { Keycode::NONE }
-            "KC_TRNS" => { Keycode::TRANSPARENT }
-
-            "KC_A" => { Keycode::KC_A }
-            "KC_B" => { Keycode::KC_B }
-            "KC_C" => { Keycode::KC_C }
-            "KC_D" => { Keycode::KC_D }
-            "KC_E" => { Keycode::KC_E }
-            "KC_F" => { Keycode::KC_F }
-            "KC_G" => { Keycode::KC_G }
-            "KC_H" => { Keycode::KC_H }
-            "KC_I" => { Keycode::KC_I }
-            "KC_J" => { Keycode::KC_J }
-            "KC_K" => { Keycode::KC_K }
-            "KC_L" => { Keycode::KC_L }
-            "KC_M" => { Keycode::KC_M }
-            "KC_N" => { Keycode::KC_N }
-            "KC_O" => { Keycode::KC_O }
-            "KC_P" => { Keycode::KC_P }
-            "KC_Q" => { Keycode::KC_Q }
-            "KC_R" => { Keycode::KC_R }
-            "KC_S" => { Keycode::KC_S }
-            "KC_T" => { Keycode::KC_T }
-            "KC_U" => { Keycode::KC_U }
-            "KC_V" => { Keycode::KC_V }
-            "KC_W" => { Keycode::KC_W }
-            "KC_X" => { Keycode::KC_X }
-            "KC_Y" => { Keycode::KC_Y }
-            "KC_Z" => { Keycode::KC_Z }
-
-            "KC_0" => { Keycode::KC_0 }
-            "KC_1" => { Keycode::KC_1 }
-            "KC_2" => { Keycode::KC_2 }
-            "KC_3" => { Keycode::KC_3 }
-            "KC_4" => { Keycode::KC_4 }
-            "KC_5" => { Keycode::KC_5 }
-            "KC_6" => { Keycode::KC_6 }
-            "KC_7" => { Keycode::KC_7 }
-            "KC_8" => { Keycode::KC_8 }
-            "KC_9" => { Keycode::KC_9 }
-
-            "KC_F1" => { Keycode::KC_F1 }
-            "KC_F2" => { Keycode::KC_F2 }
-            "KC_F3" => { Keycode::KC_F3 }
-            "KC_F4" => { Keycode::KC_F4 }
-            "KC_F5" => { Keycode::KC_F5 }
-            "KC_F6" => { Keycode::KC_F6 }
-            "KC_F7" => { Keycode::KC_F7 }
-            "KC_F8" => { Keycode::KC_F8 }
-            "KC_F9" => { Keycode::KC_F9 }
-            "KC_F10" => { Keycode::KC_F10 }
-
-            "KC_KB_VOLUME_UP" => { Keycode::KC_KB_VOLUME_UP }
-            "KC_KB_VOLUME_DOWN" => { Keycode::KC_KB_VOLUME_DOWN }
-            "KC_KB_MUTE" => { Keycode::KC_KB_MUTE }
-            "KC_MPLY" => { Keycode::KC_MEDIA_PLAY }
-            "KC_MPRV" => { Keycode::KC_MEDIA_PREV }
-            "KC_MNXT" => { Keycode::KC_MEDIA_NEXT }
-
-            "KC_MINUS" => { Keycode::KC_MINUS }
-            "KC_GRV" => { Keycode::KC_GRV }
-            "KC_QUOT" => { Keycode::KC_QUOT }
-            "KC_BSLS" => { Keycode::KC_BSLS }
-            "KC_LBRC" => { Keycode::KC_LBRC }
-            "KC_RBRC" => { Keycode::KC_RBRC }
-            "KC_EQUAL" => { Keycode::KC_EQUAL }
-            "KC_COMM" => { Keycode::KC_COMM }
-            "KC_DOT" => { Keycode::KC_DOT }
-            "KC_SLASH" => { Keycode::KC_SLASH }
-            "KC_SCLN" => { Keycode::KC_SCLN }
-
-            "MOD_LCTL" => { Keycode::MOD_LCTL }
-            "KC_BSPC" => { Keycode::KC_BSPC }
-            "KC_LGUI" => { Keycode::KC_LGUI }
-            "KC_SPACE" => { Keycode::KC_SPACE }
-            "MOD_LALT" => { Keycode::MOD_LALT }
-            "KC_TAB" => { Keycode::KC_TAB }
-            "KC_PGUP" => { Keycode::KC_PGUP }
-            "KC_PGDN" => { Keycode::KC_PGDN }
-            "KC_HOME" => { Keycode::KC_HOME }
-            "KC_END" => { Keycode::KC_END }
-            "KC_ENT" => { Keycode::KC_ENT }
-            "KC_ESC" => { Keycode::KC_ESC }
-            "KC_PSCR" => { Keycode::KC_PSCR }
-
-            k if k.starts_with("S(") && k.ends_with(')') => {
-                let inner = &k[2..(k.len() - 1)];
-                Keycode::S(Box::new(Keycode::parse_str(inner)))
+    fn parse_str(key: &str, constants: &Constants) -> Result<Self, String> {
+        if let Some(index) = key.strip_prefix("QK_MACRO_").and_then(|n| n.parse::<u8>().ok()) {
+            return Ok(Keycode::Macro(index));
+        }
+        Ok(match keycodes::table().lookup(key) {
+            Some(spec) if spec.code == 0 => Keycode::NONE,
+            Some(spec) if spec.code == 1 => Keycode::TRANSPARENT,
+            Some(spec) => Keycode::Basic(spec.code),
+            None if constants.enumerators.contains(key) || legends::table().get(key).is_some() => {
+                Keycode::Custom(key.to_string())
             }
-            k if k.starts_with("MO(") && k.ends_with(')') => {
-                let layer = &k[3..(k.len() - 1)];
-                Keycode::MO(layer.to_string())
+            // `QK_KB_n` and `QK_USER_n`, which older specs don't list
+            None if Keycode::Custom(key.to_string()).encode(constants).is_some() => {
+                Keycode::Custom(key.to_string())
             }
-            k if k.starts_with("MT(") && k.ends_with(')') => {
-                let inner = &k[3..(k.len() - 1)];
-                let parts: Vec<&str> = inner.split(',').map(|s| s.trim()).collect();
-                if parts.len() != 2 {
-                    panic!("Invalid MT() format");
-                }
-                let hold = Keycode::parse_str(parts[0]);
-                let tap = Keycode::parse_str(parts[1]);
-                Keycode::MT(Box::new(hold), Box::new(tap))
+            None => return Err(format!("unknown keycode `{}`", key)),
+        })
+    }
+
+    /// Evaluates the modifier argument of `MT()`, e.g. `MOD_LCTL | MOD_LSFT`
+    fn parse_mods(expr: &Expr, constants: &Constants) -> Result<Mods, String> {
+        match expr {
+            Expr::Ident(name) => Mods::from_mask_name(name)
+                .or_else(|| constants.values.get(name).map(|&bits| Mods::from_qmk_bits(bits as u8)))
+                .ok_or_else(|| format!("unknown modifier `{}`", name)),
+            Expr::Binary(lhs, "|" | "+", rhs) => {
+                Ok(Self::parse_mods(lhs, constants)?.union(Self::parse_mods(rhs, constants)?))
             }
-            k if k.starts_with("LT(") && k.ends_with(')') => {
-                let inner = &k[3..(k.len() - 1)];
-                let parts: Vec<&str> = inner.split(',').map(|s| s.trim()).collect();
-                if parts.len() != 2 {
-                    panic!("Invalid LT() format");
-                }
-                let layer = parts[0].to_string();
-                let keycode = Keycode::parse_str(parts[1]);
-                Keycode::LT(layer, Box::new(keycode))
+            _ => match constants.eval(expr) {
+                Some(bits) => Ok(Mods::from_qmk_bits(bits as u8)),
+                None => Err(format!("unknown modifier `{}`", expr)),
+            },
+        }
+    }
+
+    /// Wraps `inner` in modifiers, merging nested wrappers like `LCTL(LSFT(kc))`
+    fn with_mods(mods: Mods, inner: Keycode) -> Self {
+        match inner {
+            Keycode::Mods(inner_mods, key) => Keycode::Mods(mods.union(inner_mods), key),
+            _ => Keycode::Mods(mods, Box::new(inner)),
+        }
+    }
+
+    /// The spec name of a plain keycode, e.g. `KC_ESCAPE` for `KC_ESC`
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            Self::NONE => Some("KC_NO"),
+            Self::TRANSPARENT => Some("KC_TRANSPARENT"),
+            Self::Basic(code) => keycodes::table().get(*code).map(|spec| spec.key.as_str()),
+            _ => None,
+        }
+    }
+
+    /// The legend table entry for a plain or custom keycode
+    fn legend(&self) -> Option<&'static Legend> {
+        match self {
+            Self::Custom(name) => legends::table().get(name),
+            _ => legends::table().get(self.name()?),
+        }
+    }
+
+    /// Anything unknown becomes `Raw`, with a message for it added to `warnings`
+    pub fn parse(expr: &Expr, constants: &Constants, warnings: &mut Vec<String>) -> Self {
+        Self::try_parse(expr, constants, warnings).unwrap_or_else(|message| {
+            warnings.push(message);
+            Keycode::Raw(expr.to_string())
+        })
+    }
+
+    /// A keycode written out as text, like the strings of a keymap.json
+    pub fn parse_text(text: &str, constants: &Constants, warnings: &mut Vec<String>) -> Self {
+        match Expr::parse(&tokenize(text)) {
+            Ok(expr) => Self::parse(&expr, constants, warnings),
+            Err(e) => {
+                warnings.push(format!("{} in `{}`", e.message, text));
+                Keycode::Raw(text.to_string())
             }
-            _ => { panic!("Unknown keycode: {}", key); }
         }
     }
 
-    pub fn render(&self, px: f32, py: f32, pixmap: &mut Pixmap, font: &FontArc, scale: PxScale, color: Color) -> bool {
+    fn try_parse(expr: &Expr, constants: &Constants, warnings: &mut Vec<String>) -> Result<Self, String> {
+        Ok(match expr {
+            Expr::Ident(name) => Self::parse_str(name, constants)?,
+            Expr::Call(name, args) => match (name.as_str(), args.as_slice()) {
+                ("MO", [layer]) => Keycode::MO(constants.layer_ref(layer)),
+                ("TG", [layer]) => Keycode::TG(constants.layer_ref(layer)),
+                ("TO", [layer]) => Keycode::TO(constants.layer_ref(layer)),
+                ("TT", [layer]) => Keycode::TT(constants.layer_ref(layer)),
+                ("DF", [layer]) => Keycode::DF(constants.layer_ref(layer)),
+                ("PDF", [layer]) => Keycode::PDF(constants.layer_ref(layer)),
+                ("OSL", [layer]) => Keycode::OSL(constants.layer_ref(layer)),
+                ("LM", [layer, mods]) => Keycode::LM(
+                    constants.layer_ref(layer),
+                    Self::parse_mods(mods, constants)?,
+                ),
+                ("MT", [mods, tap]) => Keycode::ModTap(
+                    Self::parse_mods(mods, constants)?,
+                    Box::new(Keycode::parse(tap, constants, warnings)),
+                ),
+                ("LT", [layer, tap]) => Keycode::LT(
+                    constants.layer_ref(layer),
+                    Box::new(Keycode::parse(tap, constants, warnings)),
+                ),
+                ("OSM", [mods]) => Keycode::OSM(Self::parse_mods(mods, constants)?),
+                ("UC", [code]) => match constants.eval(code) {
+                    Some(code @ 0..0x8000) => Keycode::Raw(format!("UC({:#06x})", code)),
+                    _ => return Err(format!("unknown unicode keycode `{}`", expr)),
+                },
+                ("TD", [index]) => match constants.eval(index) {
+                    Some(index @ 0..=255) => Keycode::TD(index as u8),
+                    _ => return Err(format!("unknown tap dance `{}`", index)),
+                },
+                (name, [inner]) if Mods::from_wrapper_name(name).is_some() => Self::with_mods(
+                    Mods::from_wrapper_name(name).unwrap(),
+                    Keycode::parse(inner, constants, warnings),
+                ),
+                (name, [tap]) if Mods::from_mod_tap_name(name).is_some() => Keycode::ModTap(
+                    Mods::from_mod_tap_name(name).unwrap(),
+                    Box::new(Keycode::parse(tap, constants, warnings)),
+                ),
+                _ => return Err(format!("unknown keycode `{}`", expr)),
+            },
+            // a keycode given by its value, e.g. `0x5220`
+            Expr::Number(_) | Expr::Binary(..) => {
+                match constants.eval(expr).and_then(|value| u16::try_from(value).ok()) {
+                    Some(code) => match Self::decode(code, constants) {
+                        Keycode::Raw(_) => return Err(format!("unknown keycode {:#06x}", code)),
+                        keycode => keycode,
+                    },
+                    None => return Err(format!("unknown keycode `{}`", expr)),
+                }
+            }
+        })
+    }
+
+    /// Draws the legend inside `area`, returns false for transparent keys so the layer below
+    /// shows through
+    pub fn render(&self, area: Rect, pixmap: &mut Pixmap, glyphs: &mut GlyphCache, scale: PxScale, color: Color) -> bool {
         match self {
             Self::TRANSPARENT => {
                 return false;
             }
             Self::NONE => {},
 
-            Self::KC_A => Self::render_simple(px, py, "a", pixmap, font, scale, color),
-            Self::KC_B => Self::render_simple(px, py, "b", pixmap, font, scale, color),
-            Self::KC_C => Self::render_simple(px, py, "c", pixmap, font, scale, color),
-            Self::KC_D => Self::render_simple(px, py, "d", pixmap, font, scale, color),
-            Self::KC_E => Self::render_simple(px, py, "e", pixmap, font, scale, color),
-            Self::KC_F => Self::render_simple(px, py, "f", pixmap, font, scale, color),
-            Self::KC_G => Self::render_simple(px, py, "g", pixmap, font, scale, color),
-            Self::KC_H => Self::render_simple(px, py, "h", pixmap, font, scale, color),
-            Self::KC_I => Self::render_simple(px, py, "i", pixmap, font, scale, color),
-            Self::KC_J => Self::render_simple(px, py, "j", pixmap, font, scale, color),
-            Self::KC_K => Self::render_simple(px, py, "k", pixmap, font, scale, color),
-            Self::KC_L => Self::render_simple(px, py, "l", pixmap, font, scale, color),
-            Self::KC_M => Self::render_simple(px, py, "m", pixmap, font, scale, color),
-            Self::KC_N => Self::render_simple(px, py, "n", pixmap, font, scale, color),
-            Self::KC_O => Self::render_simple(px, py, "o", pixmap, font, scale, color),
-            Self::KC_P => Self::render_simple(px, py, "p", pixmap, font, scale, color),
-            Self::KC_Q => Self::render_simple(px, py, "q", pixmap, font, scale, color),
-            Self::KC_R => Self::render_simple(px, py, "r", pixmap, font, scale, color),
-            Self::KC_S => Self::render_simple(px, py, "s", pixmap, font, scale, color),
-            Self::KC_T => Self::render_simple(px, py, "t", pixmap, font, scale, color),
-            Self::KC_U => Self::render_simple(px, py, "u", pixmap, font, scale, color),
-            Self::KC_V => Self::render_simple(px, py, "v", pixmap, font, scale, color),
-            Self::KC_W => Self::render_simple(px, py, "w", pixmap, font, scale, color),
-            Self::KC_X => Self::render_simple(px, py, "x", pixmap, font, scale, color),
-            Self::KC_Y => Self::render_simple(px, py, "y", pixmap, font, scale, color),
-            Self::KC_Z => Self::render_simple(px, py, "z", pixmap, font, scale, color),
-
-            Self::KC_0 => Self::render_simple(px, py, "0", pixmap, font, scale, color),
-            Self::KC_1 => Self::render_simple(px, py, "1", pixmap, font, scale, color),
-            Self::KC_2 => Self::render_simple(px, py, "2", pixmap, font, scale, color),
-            Self::KC_3 => Self::render_simple(px, py, "3", pixmap, font, scale, color),
-            Self::KC_4 => Self::render_simple(px, py, "4", pixmap, font, scale, color),
-            Self::KC_5 => Self::render_simple(px, py, "5", pixmap, font, scale, color),
-            Self::KC_6 => Self::render_simple(px, py, "6", pixmap, font, scale, color),
-            Self::KC_7 => Self::render_simple(px, py, "7", pixmap, font, scale, color),
-            Self::KC_8 => Self::render_simple(px, py, "8", pixmap, font, scale, color),
-            Self::KC_9 => Self::render_simple(px, py, "9", pixmap, font, scale, color),
-
-            Self::KC_F1 => Self::render_two_chars(px, py, "F1", pixmap, font, scale, color),
-            Self::KC_F2 => Self::render_two_chars(px, py, "F2", pixmap, font, scale, color),
-            Self::KC_F3 => Self::render_two_chars(px, py, "F3", pixmap, font, scale, color),
-            Self::KC_F4 => Self::render_two_chars(px, py, "F4", pixmap, font, scale, color),
-            Self::KC_F5 => Self::render_two_chars(px, py, "F5", pixmap, font, scale, color),
-            Self::KC_F6 => Self::render_two_chars(px, py, "F6", pixmap, font, scale, color),
-            Self::KC_F7 => Self::render_two_chars(px, py, "F7", pixmap, font, scale, color),
-            Self::KC_F8 => Self::render_two_chars(px, py, "F8", pixmap, font, scale, color),
-            Self::KC_F9 => Self::render_two_chars(px, py, "F9", pixmap, font, scale, color),
-            Self::KC_F10 => Self::render_three_chars(px, py, "F10", pixmap, font, scale, color),
-
-            Self::KC_KB_VOLUME_UP => Self::render_two_chars(px, py, "V+", pixmap, font, scale, color),
-            Self::KC_KB_VOLUME_DOWN => Self::render_two_chars(px, py, "V-", pixmap, font, scale, color),
-            Self::KC_KB_MUTE => Self::render_three_chars(px, py, "mut", pixmap, font, scale, color),
-            Self::KC_MEDIA_PLAY => Self::render_two_chars(px, py, "▶", pixmap, font, scale, color),
-            Self::KC_MEDIA_PREV => Self::render_three_chars(px, py, "prev", pixmap, font, scale, color),
-            Self::KC_MEDIA_NEXT => Self::render_three_chars(px, py, "next", pixmap, font, scale, color),
-
-            Self::KC_MINUS => Self::render_simple(px, py, "-", pixmap, font, scale, color),
-            Self::KC_GRV => Self::render_simple(px, py, "`", pixmap, font, scale, color),
-            Self::KC_QUOT => Self::render_simple(px, py, "'", pixmap, font, scale, color),
-            Self::KC_BSLS => Self::render_simple(px, py, "\\", pixmap, font, scale, color),
-            Self::KC_LBRC => Self::render_simple(px, py, "[", pixmap, font, scale, color),
-            Self::KC_RBRC => Self::render_simple(px, py, "]", pixmap, font, scale, color),
-            Self::KC_EQUAL => Self::render_simple(px, py, "=", pixmap, font, scale, color),
-            Self::KC_COMM => Self::render_simple(px, py, ",", pixmap, font, scale, color),
-            Self::KC_DOT => Self::render_simple(px, py, ".", pixmap, font, scale, color),
-            Self::KC_SLASH => Self::render_simple(px, py, "/", pixmap, font, scale, color),
-            Self::KC_SCLN => Self::render_simple(px, py, ";", pixmap, font, scale, color),
-            
-            Self::MOD_LCTL => Self::render_three_chars(px, py, "Ctl", pixmap, font, scale, color),
-            Self::KC_BSPC =>  Self::render_three_chars(px, py, "Bsp", pixmap, font, scale, color),
-            Self::KC_LGUI =>  Self::render_three_chars(px, py, "Mod", pixmap, font, scale, color),
-            Self::KC_SPACE => Self::render_simple(px, py, "␣", pixmap, font, scale, color),
-            Self::MOD_LALT => Self::render_three_chars(px, py, "Alt", pixmap, font, scale, color),
-            Self::KC_TAB =>   Self::render_three_chars(px, py, "Tab", pixmap, font, scale, color),
-            Self::KC_PGUP =>  Self::render_three_chars(px, py, "PgU", pixmap, font, scale, color),
-            Self::KC_PGDN =>  Self::render_three_chars(px, py, "PgD", pixmap, font, scale, color),
-            Self::KC_HOME =>  Self::render_three_chars(px, py, "Hom", pixmap, font, scale, color),
-            Self::KC_END =>   Self::render_three_chars(px, py, "End", pixmap, font, scale, color),
-            Self::KC_ENT =>   Self::render_three_chars(px, py, "Ent", pixmap, font, scale, color),
-            Self::KC_ESC =>   Self::render_three_chars(px, py, "Esc", pixmap, font, scale, color),
-            Self::KC_PSCR =>  Self::render_three_chars(px, py, "PSc", pixmap, font, scale, color),
-
-
-            Self::S(inner) => {
-                match **inner {
-                    Self::KC_A => Self::render_simple(px, py, "A", pixmap, font, scale, color),
-                    Self::KC_B => Self::render_simple(px, py, "B", pixmap, font, scale, color),
-                    Self::KC_C => Self::render_simple(px, py, "C", pixmap, font, scale, color),
-                    Self::KC_D => Self::render_simple(px, py, "D", pixmap, font, scale, color),
-                    Self::KC_E => Self::render_simple(px, py, "E", pixmap, font, scale, color),
-                    Self::KC_F => Self::render_simple(px, py, "F", pixmap, font, scale, color),
-                    Self::KC_G => Self::render_simple(px, py, "G", pixmap, font, scale, color),
-                    Self::KC_H => Self::render_simple(px, py, "H", pixmap, font, scale, color),
-                    Self::KC_I => Self::render_simple(px, py, "I", pixmap, font, scale, color),
-                    Self::KC_J => Self::render_simple(px, py, "J", pixmap, font, scale, color),
-                    Self::KC_K => Self::render_simple(px, py, "K", pixmap, font, scale, color),
-                    Self::KC_L => Self::render_simple(px, py, "L", pixmap, font, scale, color),
-                    Self::KC_M => Self::render_simple(px, py, "M", pixmap, font, scale, color),
-                    Self::KC_N => Self::render_simple(px, py, "N", pixmap, font, scale, color),
-                    Self::KC_O => Self::render_simple(px, py, "O", pixmap, font, scale, color),
-                    Self::KC_P => Self::render_simple(px, py, "P", pixmap, font, scale, color),
-                    Self::KC_Q => Self::render_simple(px, py, "Q", pixmap, font, scale, color),
-                    Self::KC_R => Self::render_simple(px, py, "R", pixmap, font, scale, color),
-                    Self::KC_S => Self::render_simple(px, py, "S", pixmap, font, scale, color),
-                    Self::KC_T => Self::render_simple(px, py, "T", pixmap, font, scale, color),
-                    Self::KC_U => Self::render_simple(px, py, "U", pixmap, font, scale, color),
-                    Self::KC_V => Self::render_simple(px, py, "V", pixmap, font, scale, color),
-                    Self::KC_W => Self::render_simple(px, py, "W", pixmap, font, scale, color),
-                    Self::KC_X => Self::render_simple(px, py, "X", pixmap, font, scale, color),
-                    Self::KC_Y => Self::render_simple(px, py, "Y", pixmap, font, scale, color),
-                    Self::KC_Z => Self::render_simple(px, py, "Z", pixmap, font, scale, color),
-
-                    Self::KC_0 => Self::render_simple(px, py, ")", pixmap, font, scale, color),
-                    Self::KC_1 => Self::render_simple(px, py, "!", pixmap, font, scale, color),
-                    Self::KC_2 => Self::render_simple(px, py, "@", pixmap, font, scale, color),
-                    Self::KC_3 => Self::render_simple(px, py, "#", pixmap, font, scale, color),
-                    Self::KC_4 => Self::render_simple(px, py, "$", pixmap, font, scale, color),
-                    Self::KC_5 => Self::render_simple(px, py, "%", pixmap, font, scale, color),
-                    Self::KC_6 => Self::render_simple(px, py, "^", pixmap, font, scale, color),
-                    Self::KC_7 => Self::render_simple(px, py, "&", pixmap, font, scale, color),
-                    Self::KC_8 => Self::render_simple(px, py, "*", pixmap, font, scale, color),
-                    Self::KC_9 => Self::render_simple(px, py, "(", pixmap, font, scale, color),
-
-                    Self::KC_MINUS => Self::render_simple(px, py, "_", pixmap, font, scale, color),
-                    Self::KC_GRV => Self::render_simple(px, py, "~", pixmap, font, scale, color),
-                    Self::KC_QUOT => Self::render_simple(px, py, "\"", pixmap, font, scale, color),
-                    Self::KC_BSLS => Self::render_simple(px, py, "|", pixmap, font, scale, color),
-                    Self::KC_LBRC => Self::render_simple(px, py, "{", pixmap, font, scale, color),
-                    Self::KC_RBRC => Self::render_simple(px, py, "}", pixmap, font, scale, color),
-                    Self::KC_EQUAL => Self::render_simple(px, py, "+", pixmap, font, scale, color),
-                    Self::KC_COMM => Self::render_simple(px, py, "<", pixmap, font, scale, color),
-                    Self::KC_DOT => Self::render_simple(px, py, ">", pixmap, font, scale, color),
-                    Self::KC_SLASH => Self::render_simple(px, py, "?", pixmap, font, scale, color),
-                    Self::KC_SCLN => Self::render_simple(px, py, ":", pixmap, font, scale, color),
-
-                    _ => Self::render_two_chars(px, py, "<>", pixmap, font, scale, color),
+            Self::Raw(text) => draw_text_fitted(pixmap, text, glyphs, scale, area, color),
+            Self::Basic(_) | Self::Custom(_) => match self.legend() {
+                Some(Legend { icon: Some(icon), .. }) => draw_text_fitted(pixmap, icon, glyphs, scale, area, color),
+                Some(Legend { label: Some(label), .. }) => draw_text_fitted(pixmap, label, glyphs, scale, area, color),
+                _ => draw_text_fitted(pixmap, &self.label(), glyphs, scale, area, color),
+            },
+
+            Self::Mods(mods, inner) if mods.is_shift_only() => {
+                match inner.legend().and_then(|legend| legend.shifted.as_deref()) {
+                    Some(shifted) => draw_text_fitted(pixmap, shifted, glyphs, scale, area, color),
+                    None => Self::render_with_mods(area, *mods, inner, pixmap, glyphs, scale, color),
                 }
             },
-            Self::MO(layer) => {
-                match layer.as_str() {
-                    "_SHIFT" => Self::render_simple(px, py, "↑", pixmap, font, scale, color),
-                    "_NUMBERS" => Self::render_three_chars(px, py, "123", pixmap, font, scale, color),
-                    "_SYMBOLS" => Self::render_three_chars(px, py, "#+=", pixmap, font, scale, color),
-                    _ => {}
-                };
+            Self::Mods(mods, inner) => Self::render_with_mods(area, *mods, inner, pixmap, glyphs, scale, color),
+            Self::MO(layer) => Self::render_layer(area, "MO", layer, pixmap, glyphs, scale, color),
+            Self::TG(layer) => Self::render_layer(area, "TG", layer, pixmap, glyphs, scale, color),
+            Self::TO(layer) => Self::render_layer(area, "TO", layer, pixmap, glyphs, scale, color),
+            Self::TT(layer) => Self::render_layer(area, "TT", layer, pixmap, glyphs, scale, color),
+            Self::DF(layer) => Self::render_layer(area, "DF", layer, pixmap, glyphs, scale, color),
+            Self::PDF(layer) => Self::render_layer(area, "PDF", layer, pixmap, glyphs, scale, color),
+            Self::OSL(layer) => Self::render_layer(area, "OSL", layer, pixmap, glyphs, scale, color),
+            Self::LM(layer, mods) => {
+                let badge = format!("LM{}", mods.symbols());
+                Self::render_layer(area, &badge, layer, pixmap, glyphs, scale, color);
+            }
+            Self::OSM(mods) => {
+                let (top, bottom) = Self::split(area);
+                draw_text_fitted(pixmap, "OSM", glyphs, scale, top, color);
+                draw_text_fitted(pixmap, &mods.symbols(), glyphs, scale, bottom, color);
             }
+            Self::TD(index) => Self::render_layer(area, "TD", &index.to_string(), pixmap, glyphs, scale, color),
+            Self::Macro(index) => Self::render_layer(area, "MACRO", &index.to_string(), pixmap, glyphs, scale, color),
 
             Self::LT(layer, key) => {
-                match layer.as_str() {
-                    "_SHIFT" => Self::render_simple(px, py - 10.0, "↑", pixmap, font, scale, color),
-                    "_NUMBERS" => Self::render_three_chars(px, py - 10.0, "123", pixmap, font, scale, color),
-                    "_SYMBOLS" => Self::render_three_chars(px, py - 10.0, "#+=", pixmap, font, scale, color),
-                    _ => {}
-                };
-                key.render(px, py + 15.0, pixmap, font, scale, color);
+                let (top, bottom) = Self::split(area);
+                draw_text_fitted(pixmap, &Self::layer_legend(layer), glyphs, scale, top, color);
+                key.render(bottom, pixmap, glyphs, scale, color);
             }
-            Self::MT(hold, tap) => {
-                hold.render(px, py - 10.0, pixmap, font, scale, color);
-                tap.render(px, py + 15.0, pixmap, font, scale, color);
+            Self::ModTap(mods, tap) => {
+                let (top, bottom) = Self::split(area);
+                draw_text_fitted(pixmap, &mods.symbols(), glyphs, scale, top, color);
+                tap.render(bottom, pixmap, glyphs, scale, color);
             }
         };
-        return true;
+        true
+    }
+
+    /// The spec label, or the name without its `KC_` prefix
+    pub fn label(&self) -> String {
+        let Self::Basic(code) = self else {
+            return self.to_string();
+        };
+        match keycodes::table().get(*code) {
+            Some(spec) => spec.label.clone().unwrap_or_else(|| {
+                spec.key.trim_start_matches("KC_").to_string()
+            }),
+            None => format!("{:#06x}", code),
+        }
     }
 
-    //for single character keys
-    fn render_simple(px: f32, py: f32, text: &str, pixmap: &mut Pixmap, font: &FontArc, scale: PxScale, color: Color) {
-        draw_text(pixmap, text, font, scale, px + 17.0, py + 17.0, color);
+    /// Short legend for a layer: its icon or label from the legend table, otherwise the name
+    /// without its leading underscore
+    fn layer_legend(layer: &str) -> String {
+        match legends::table().get(layer) {
+            Some(Legend { icon: Some(text), .. } | Legend { label: Some(text), .. }) => text.clone(),
+            _ => layer.trim_start_matches('_').to_string(),
+        }
+    }
+
+    // the kind of layer switch as a small badge in the corner, the target layer below it
+    #[allow(clippy::too_many_arguments)]
+    fn render_layer(area: Rect, badge: &str, layer: &str, pixmap: &mut Pixmap, glyphs: &mut GlyphCache, scale: PxScale, color: Color) {
+        let badge_scale = PxScale { x: scale.x * 0.4, y: scale.y * 0.4 };
+        let (_, badge_height) = measure_text(badge, glyphs.font(), badge_scale);
+        let (x, y) = (area.x() + scale.x * 0.1, area.y() + scale.y * 0.07);
+        draw_text(pixmap, badge, glyphs, badge_scale, x, y, color);
+        let below = Rect::from_ltrb(area.left(), area.top() + badge_height, area.right(), area.bottom())
+            .unwrap_or(area);
+        draw_text_fitted(pixmap, &Self::layer_legend(layer), glyphs, scale, below, color);
+    }
+
+    // modifier symbols on top, the key they apply to below
+    #[allow(clippy::too_many_arguments)]
+    fn render_with_mods(area: Rect, mods: Mods, inner: &Keycode, pixmap: &mut Pixmap, glyphs: &mut GlyphCache, scale: PxScale, color: Color) {
+        let (top, bottom) = Self::split(area);
+        draw_text_fitted(pixmap, &format!("{}+", mods.symbols()), glyphs, scale, top, color);
+        inner.render(bottom, pixmap, glyphs, scale, color);
+    }
+
+    // upper and lower half, for keys with two legends
+    fn split(area: Rect) -> (Rect, Rect) {
+        let middle = area.top() + area.height() / 2.0;
+        (
+            Rect::from_ltrb(area.left(), area.top(), area.right(), middle).unwrap(),
+            Rect::from_ltrb(area.left(), middle, area.right(), area.bottom()).unwrap(),
+        )
+    }
+}
+
+impl std::fmt::Display for Keycode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Custom(name) | Self::Raw(name) => write!(f, "{}", name),
+            Self::MO(layer) => write!(f, "MO({})", layer),
+            Self::TG(layer) => write!(f, "TG({})", layer),
+            Self::TO(layer) => write!(f, "TO({})", layer),
+            Self::TT(layer) => write!(f, "TT({})", layer),
+            Self::DF(layer) => write!(f, "DF({})", layer),
+            Self::PDF(layer) => write!(f, "PDF({})", layer),
+            Self::OSL(layer) => write!(f, "OSL({})", layer),
+            Self::LM(layer, mods) => write!(f, "LM({}, {})", layer, mods),
+            Self::LT(layer, tap) => write!(f, "LT({}, {})", layer, tap),
+            Self::Mods(mods, inner) => match mods.wrapper_name() {
+                Some(wrapper) => write!(f, "{}({})", wrapper, inner),
+                // no single wrapper for this combination, nest them
+                None => {
+                    let mut closing = 0;
+                    for bit in 0..8 {
+                        if let Some(wrapper) = Mods(mods.0 & (1 << bit)).wrapper_name() {
+                            write!(f, "{}(", wrapper)?;
+                            closing += 1;
+                        }
+                    }
+                    write!(f, "{}{}", inner, ")".repeat(closing))
+                }
+            },
+            Self::ModTap(mods, tap) => write!(f, "MT({}, {})", mods, tap),
+            Self::OSM(mods) => write!(f, "OSM({})", mods),
+            Self::TD(index) => write!(f, "TD({})", index),
+            Self::Macro(index) => write!(f, "QK_MACRO_{}", index),
+            Self::Basic(code) if self.name().is_none() => write!(f, "{:#06x}", code),
+            _ => write!(f, "{}", self.name().unwrap_or_default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> Option<i64> {
+        Constants::default().eval(&Expr::parse(&tokenize(source)).unwrap())
+    }
+
+    fn parse_keymap(source: &str) -> Result<Option<(String, Vec<Layer>)>, Diagnostic> {
+        let path = Path::new("keymap.c");
+        let tokens = Preprocessor::new(Vec::new()).process_source(source, path);
+        parse_tokens(&tokens, path, &["LAYOUT".to_string()], &mut Vec::new())
+    }
+
+    #[test]
+    fn eval_overflow() {
+        assert_eq!(eval("1 << 4 | 1"), Some(17));
+        assert_eq!(eval("1 << 70"), None);
+        assert_eq!(eval("256 >> 64"), None);
+        assert_eq!(eval("1 << (0 - 1)"), None);
+        assert_eq!(eval("0x7fffffffffffffff + 1"), None);
+    }
+
+    #[test]
+    fn unmatched_bracket() {
+        let source = "keymaps[][1][3] = {\n    [0] = LAYOUT(KC_A, KC_B, LT(1, KC_C),\n    [1] = LAYOUT(KC_D, KC_E, KC_F)\n};";
+        let error = parse_keymap(source).err().unwrap();
+        assert_eq!(error.message, "unmatched `(`");
+        let span = error.span.unwrap();
+        assert_eq!((span.line, span.col, span.len), (2, 11, 7));
+    }
+
+    #[test]
+    fn layer_index_range() {
+        let layers = parse_keymap("keymaps[][1][1] = { [0] = LAYOUT(KC_A), [2] = LAYOUT(KC_B) };")
+            .unwrap()
+            .unwrap()
+            .1;
+        assert_eq!(layers.len(), 3);
+        for designator in ["0 - 1", "32", "1 << 40"] {
+            let source = format!("keymaps[][1][1] = {{ [{}] = LAYOUT(KC_A) }};", designator);
+            let error = parse_keymap(&source).err().unwrap();
+            assert!(error.message.contains("out of range"), "{}", error.message);
+            assert!(error.span.is_some());
+        }
     }
 
-    fn render_two_chars(px: f32, py: f32, text: &str, pixmap: &mut Pixmap, font: &FontArc, scale: PxScale, color: Color) {
-        draw_text(pixmap, text, font, scale, px + 5.0, py + 17.0, color);
+    #[test]
+    fn enum_layer_names() {
+        let source = "enum { _BASE, _NAV };\nkeymaps[][1][1] = { LAYOUT(TG(_NAV)), LAYOUT(TG(_BASE)) };";
+        let layers = parse_keymap(source).unwrap().unwrap().1;
+        let names = layers.iter().map(|l| l.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["_BASE", "_NAV"]);
+        assert_eq!(layers[0].keys[0].to_string(), "TG(_NAV)");
+        assert_eq!(layers[1].keys[0].to_string(), "TG(_BASE)");
     }
 
-    fn render_three_chars(px: f32, py: f32, text: &str, pixmap: &mut Pixmap, font: &FontArc, scale: PxScale, color: Color) {
-        let new_scale = PxScale { x: scale.x * 0.8, y: scale.y * 0.8 };
-        draw_text(pixmap, text, font, new_scale, px + 1.0, py + 17.0, color);
+    #[test]
+    fn layer_legends() {
+        assert_eq!(Keycode::layer_legend("_NUMBERS"), "NUMBERS");
+        assert_eq!(Keycode::layer_legend("2"), "2");
     }
 }
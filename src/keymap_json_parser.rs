@@ -0,0 +1,125 @@
+use std::path::Path;
+
+use json::JsonValue;
+
+use crate::diagnostic::Diagnostic;
+use crate::keymap_c_parser::{Constants, Keycode, Layer};
+
+/// A keymap.json as QMK Configurator and `qmk c2json` write it:
+///
+/// ```json
+/// { "keyboard": "planck/rev6", "layout": "LAYOUT_ortho_4x12",
+///   "layers": [["KC_ESC", "LT(1, KC_SPC)", ...], ...] }
+/// ```
+pub struct JsonKeymap {
+    /// The keyboard the keymap was made for, which has the physical layout
+    pub keyboard: Option<String>,
+    pub layout: String,
+    pub layers: Vec<Layer>,
+}
+
+/// Whether a keymap path should be read as a keymap.json rather than C
+pub fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+/// Reads a keymap.json
+pub fn parse_json_keymap(path: &Path, warnings: &mut Vec<Diagnostic>) -> Result<JsonKeymap, Diagnostic> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+    let keymap = json::parse(&text).map_err(|e| format!("can't parse {}: {}", path.display(), e))?;
+    keymap_from_json(&keymap, path, warnings)
+}
+
+/// `parse_json_keymap` on the parsed contents of the file at `path`
+fn keymap_from_json(
+    keymap: &JsonValue,
+    path: &Path,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<JsonKeymap, Diagnostic> {
+    let layout = keymap["layout"]
+        .as_str()
+        .ok_or_else(|| format!("{} has no `layout`", path.display()))?;
+    if !keymap["layers"].is_array() {
+        // a keymap.json next to a keymap.c may only hold settings
+        return Err(Diagnostic::error(format!("{} has no `layers`", path.display()), None)
+            .with_note("a keymap.json used with a keymap.c can't be shown on its own"));
+    }
+    if keymap["layers"].is_empty() {
+        return Err(format!("{} has no layers", path.display()).into());
+    }
+
+    // the layers have no names, just indices
+    let constants = Constants::default();
+    let layers = keymap["layers"]
+        .members()
+        .enumerate()
+        .map(|(index, keys)| Layer {
+            name: index.to_string(),
+            keys: keys
+                .members()
+                .enumerate()
+                .map(|(position, key)| {
+                    let mut messages = Vec::new();
+                    let keycode = parse_key(key, &constants, &mut messages);
+                    warnings.extend(messages.into_iter().map(|message| {
+                        Diagnostic::warning(
+                            format!("{}: layer {}, key {}: {}", path.display(), index, position, message),
+                            None,
+                        )
+                    }));
+                    keycode
+                })
+                .collect(),
+        })
+        .collect();
+
+    Ok(JsonKeymap {
+        keyboard: keymap["keyboard"].as_str().map(str::to_string),
+        layout: layout.to_string(),
+        layers,
+    })
+}
+
+fn parse_key(key: &JsonValue, constants: &Constants, warnings: &mut Vec<String>) -> Keycode {
+    if let Some(code) = key.as_u16() {
+        return Keycode::decode(code, constants);
+    }
+    let Some(text) = key.as_str() else {
+        warnings.push(format!("expected a keycode, found {}", key.dump()));
+        return Keycode::Raw(key.dump());
+    };
+    let text = text.trim();
+    // Configurator wraps keycodes it has no button for in `ANY()`
+    let text = text
+        .strip_prefix("ANY(")
+        .and_then(|inner| inner.strip_suffix(')'))
+        .unwrap_or(text);
+    Keycode::parse_text(text, constants, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<JsonKeymap, Diagnostic> {
+        keymap_from_json(&json::parse(source).unwrap(), Path::new("keymap.json"), &mut Vec::new())
+    }
+
+    #[test]
+    fn layers() {
+        let keymap = parse(
+            r#"{"keyboard": "planck/rev6", "layout": "LAYOUT", "layers": [["KC_A", "ANY(LT(1, KC_SPC))", 21024]]}"#,
+        )
+        .unwrap();
+        assert_eq!(keymap.keyboard.as_deref(), Some("planck/rev6"));
+        let keys = keymap.layers[0].keys.iter().map(Keycode::to_string).collect::<Vec<_>>();
+        assert_eq!(keys, ["KC_A", "LT(1, KC_SPACE)", "MO(0)"]);
+    }
+
+    #[test]
+    fn no_layers() {
+        let error = parse(r#"{"layout": "LAYOUT", "layers": []}"#).err().unwrap();
+        assert!(error.message.ends_with("has no layers"), "{}", error.message);
+    }
+}
@@ -0,0 +1,452 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use json::JsonValue;
+
+use crate::diagnostic::Diagnostic;
+use crate::keymap_c_parser::{Constants, Keycode, Layer};
+
+/// Whether a keymap file is a Vial `.vil` or a VIA backup rather than a keymap.json
+pub fn is_backup(path: &Path) -> bool {
+    if path.extension().is_some_and(|ext| ext == "vil") {
+        return true;
+    }
+    // VIA saves plain .json, told apart by the USB id a keymap.json doesn't have
+    path.extension().is_some_and(|ext| ext == "json")
+        && std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| json::parse(&text).ok())
+            .is_some_and(|backup| backup.has_key("vendorProductId") || backup.has_key("uid"))
+}
+
+pub struct TapDance {
+    pub on_tap: Keycode,
+    pub on_hold: Keycode,
+    pub on_double_tap: Keycode,
+    pub on_tap_hold: Keycode,
+    pub tapping_term: u32,
+}
+
+pub struct Combo {
+    pub keys: Vec<Keycode>,
+    pub output: Keycode,
+}
+
+pub enum MacroAction {
+    Text(String),
+    Tap(Vec<Keycode>),
+    Down(Vec<Keycode>),
+    Up(Vec<Keycode>),
+    Delay(u32), // milliseconds
+}
+
+/// What the `TD()` and macro keys of a backup refer to, and its combos
+#[derive(Default)]
+pub struct KeymapTables {
+    pub tap_dances: Vec<TapDance>,
+    pub combos: Vec<Combo>,
+    pub macros: Vec<Vec<MacroAction>>,
+}
+
+impl KeymapTables {
+    /// One line per entry in use, to list below the keyboard. Backups have a slot for every
+    /// entry the firmware has room for, the empty ones are left out.
+    pub fn lines(&self) -> Vec<String> {
+        let is_none = |key: &Keycode| matches!(key, Keycode::NONE);
+        let mut lines = Vec::new();
+        for (index, dance) in self.tap_dances.iter().enumerate() {
+            let actions = [
+                ("tap", &dance.on_tap),
+                ("hold", &dance.on_hold),
+                ("double tap", &dance.on_double_tap),
+                ("tap+hold", &dance.on_tap_hold),
+            ]
+            .into_iter()
+            .filter(|(_, key)| !is_none(key))
+            .map(|(action, key)| format!("{} {}", action, key.label()))
+            .collect::<Vec<_>>();
+            if !actions.is_empty() {
+                lines.push(format!(
+                    "TD({})  {}  ({} ms)",
+                    index,
+                    actions.join(", "),
+                    dance.tapping_term
+                ));
+            }
+        }
+        for (index, combo) in self.combos.iter().enumerate() {
+            let keys = combo.keys.iter().filter(|key| !is_none(key)).collect::<Vec<_>>();
+            if keys.is_empty() || is_none(&combo.output) {
+                continue;
+            }
+            let keys = keys.iter().map(|key| key.label()).collect::<Vec<_>>();
+            lines.push(format!(
+                "Combo {}  {} → {}",
+                index,
+                keys.join(" + "),
+                combo.output.label()
+            ));
+        }
+        for (index, actions) in self.macros.iter().enumerate() {
+            if actions.is_empty() {
+                continue;
+            }
+            let labels = |keys: &[Keycode]| {
+                keys.iter().map(|key| key.label()).collect::<Vec<_>>().join("+")
+            };
+            let actions = actions
+                .iter()
+                .map(|action| match action {
+                    MacroAction::Text(text) => format!("{:?}", text),
+                    MacroAction::Tap(keys) => labels(keys),
+                    MacroAction::Down(keys) => format!("{}↓", labels(keys)),
+                    MacroAction::Up(keys) => format!("{}↑", labels(keys)),
+                    MacroAction::Delay(ms) => format!("{} ms", ms),
+                })
+                .collect::<Vec<_>>();
+            lines.push(format!("M{}  {}", index, actions.join(" ")));
+        }
+        lines
+    }
+}
+
+/// The layers of a backup in the order of the layout's keys, and its tables
+pub struct Backup {
+    pub layers: Vec<Layer>,
+    pub tables: KeymapTables,
+}
+
+/// Reads a Vial `.vil` or a VIA backup onto the layout keys at `matrix`. VIA stores each layer
+/// as one row after another, hence `matrix_cols`.
+pub fn parse_backup(
+    path: &Path,
+    matrix: &[Option<(u8, u8)>],
+    matrix_cols: usize,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<Backup, Diagnostic> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+    let backup = json::parse(&text).map_err(|e| format!("can't parse {}: {}", path.display(), e))?;
+    backup_from_json(&backup, path, matrix, matrix_cols, warnings)
+}
+
+/// `parse_backup` on the parsed contents of the file at `path`
+fn backup_from_json(
+    backup: &JsonValue,
+    path: &Path,
+    matrix: &[Option<(u8, u8)>],
+    matrix_cols: usize,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<Backup, Diagnostic> {
+    let mut messages = Vec::new();
+
+    let layers = if backup["layout"].is_array() {
+        // Vial: layers of rows of columns, -1 where the matrix has no switch
+        backup["layout"]
+            .members()
+            .enumerate()
+            .map(|(index, rows)| Layer {
+                name: index.to_string(),
+                keys: matrix
+                    .iter()
+                    .map(|position| match position {
+                        Some((row, col)) => {
+                            let key = &rows[*row as usize][*col as usize];
+                            parse_key(key, &mut messages)
+                        }
+                        None => Keycode::NONE,
+                    })
+                    .collect(),
+            })
+            .collect::<Vec<_>>()
+    } else if backup["layers"].is_array() {
+        if matrix_cols == 0 {
+            return Err(format!("{}: the keyboard's matrix size is unknown", path.display()).into());
+        }
+        // VIA: every layer is the whole matrix, row by row
+        backup["layers"]
+            .members()
+            .enumerate()
+            .map(|(index, keys)| Layer {
+                name: index.to_string(),
+                keys: matrix
+                    .iter()
+                    .map(|position| match position {
+                        Some((row, col)) => {
+                            let key = &keys[*row as usize * matrix_cols + *col as usize];
+                            parse_key(key, &mut messages)
+                        }
+                        None => Keycode::NONE,
+                    })
+                    .collect(),
+            })
+            .collect::<Vec<_>>()
+    } else {
+        return Err(format!("{} has no `layout` or `layers`", path.display()).into());
+    };
+    if layers.is_empty() {
+        return Err(format!("{} has no layers", path.display()).into());
+    }
+
+    let mut tables = KeymapTables::default();
+    for dance in backup["tap_dance"].members() {
+        tables.tap_dances.push(TapDance {
+            on_tap: parse_key(&dance[0], &mut messages),
+            on_hold: parse_key(&dance[1], &mut messages),
+            on_double_tap: parse_key(&dance[2], &mut messages),
+            on_tap_hold: parse_key(&dance[3], &mut messages),
+            tapping_term: dance[4].as_u32().unwrap_or(0),
+        });
+    }
+    for combo in backup["combo"].members() {
+        let mut keys = combo
+            .members()
+            .map(|key| parse_key(key, &mut messages))
+            .collect::<Vec<_>>();
+        // the last entry is what the combo sends
+        let output = keys.pop().unwrap_or(Keycode::NONE);
+        tables.combos.push(Combo { keys, output });
+    }
+    // Vial has lists of actions, VIA strings like `Hello{KC_ENT}`
+    for actions in backup["macro"].members() {
+        let actions = actions
+            .members()
+            .filter_map(|action| parse_vial_action(action, &mut messages))
+            .collect();
+        tables.macros.push(actions);
+    }
+    for text in backup["macros"].members() {
+        let text = text.as_str().unwrap_or_default();
+        tables.macros.push(parse_via_macro(text, &mut messages));
+    }
+
+    // the same unknown keycode tends to sit on every layer
+    let mut seen = HashSet::new();
+    messages.retain(|message| seen.insert(message.clone()));
+    warnings.extend(
+        messages
+            .into_iter()
+            .map(|message| Diagnostic::warning(format!("{}: {}", path.display(), message), None)),
+    );
+    Ok(Backup { layers, tables })
+}
+
+/// A key as Vial or VIA store it: a keycode name, a number, or a number in a string
+fn parse_key(key: &JsonValue, warnings: &mut Vec<String>) -> Keycode {
+    if let Some(code) = key.as_u16() {
+        return from_code(code, warnings);
+    }
+    let Some(text) = key.as_str() else {
+        // -1 marks matrix positions without a switch
+        return Keycode::NONE;
+    };
+    let text = text.trim();
+    if let Some(code) = text
+        .strip_prefix("0x")
+        .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+    {
+        return from_code(code, warnings);
+    }
+    // Vial writes macros as `M0`, VIA as `MACRO00` or `MACRO(0)`
+    let macro_index = text
+        .strip_prefix("MACRO(")
+        .and_then(|rest| rest.strip_suffix(')'))
+        .or_else(|| text.strip_prefix("MACRO"))
+        .or_else(|| text.strip_prefix('M'))
+        .and_then(|index| index.parse::<u8>().ok());
+    if let Some(index) = macro_index {
+        return Keycode::Macro(index);
+    }
+    // keyboard keycodes are `USER00` in Vial and `CUSTOM(0)` in VIA
+    let kb_index = text
+        .strip_prefix("CUSTOM(")
+        .and_then(|rest| rest.strip_suffix(')'))
+        .or_else(|| text.strip_prefix("USER"))
+        .and_then(|index| index.parse::<u8>().ok());
+    if let Some(index) = kb_index {
+        return Keycode::Custom(format!("QK_KB_{}", index));
+    }
+    Keycode::parse_text(&rename_legacy(text), &Constants::default(), warnings)
+}
+
+/// Names from before QMK's keycode overhaul, which Vial still writes, and what they are now
+const LEGACY_NAMES: &[(&str, &str)] = &[
+    ("KC_LCTRL", "KC_LEFT_CTRL"),
+    ("KC_LSHIFT", "KC_LEFT_SHIFT"),
+    ("KC_RCTRL", "KC_RIGHT_CTRL"),
+    ("KC_RSHIFT", "KC_RIGHT_SHIFT"),
+    ("KC_LWIN", "KC_LEFT_GUI"),
+    ("KC_RWIN", "KC_RIGHT_GUI"),
+    ("KC_BSPACE", "KC_BACKSPACE"),
+    ("KC_SCOLON", "KC_SEMICOLON"),
+    ("KC_LBRACKET", "KC_LEFT_BRACKET"),
+    ("KC_RBRACKET", "KC_RIGHT_BRACKET"),
+    ("KC_BSLASH", "KC_BACKSLASH"),
+    ("KC_NONUS_BSLASH", "KC_NONUS_BACKSLASH"),
+    ("KC_CAPSLOCK", "KC_CAPS_LOCK"),
+    ("KC_NUMLOCK", "KC_NUM_LOCK"),
+    ("KC_SCROLLLOCK", "KC_SCROLL_LOCK"),
+    ("KC_PSCREEN", "KC_PRINT_SCREEN"),
+    ("KC_PGUP", "KC_PAGE_UP"),
+    ("KC_PGDOWN", "KC_PAGE_DOWN"),
+    ("KC_LOCKING_CAPS", "KC_LOCKING_CAPS_LOCK"),
+    ("KC_LOCKING_NUM", "KC_LOCKING_NUM_LOCK"),
+    ("KC_LOCKING_SCROLL", "KC_LOCKING_SCROLL_LOCK"),
+    ("KC__MUTE", "KC_KB_MUTE"),
+    ("KC__VOLUP", "KC_KB_VOLUME_UP"),
+    ("KC__VOLDOWN", "KC_KB_VOLUME_DOWN"),
+    ("KC_TRNS", "KC_TRANSPARENT"),
+    ("KC_GESC", "QK_GRAVE_ESCAPE"),
+    ("KC_LSPO", "SC_LSPO"),
+    ("KC_RSPC", "SC_RSPC"),
+    ("KC_LCPO", "SC_LCPO"),
+    ("KC_RCPC", "SC_RCPC"),
+    ("KC_LAPO", "SC_LAPO"),
+    ("KC_RAPC", "SC_RAPC"),
+    ("KC_SFTENT", "SC_SENT"),
+    ("RESET", "QK_BOOT"),
+    ("DEBUG", "QK_DEBUG_TOGGLE"),
+    ("EEPROM_RESET", "QK_CLEAR_EEPROM"),
+];
+
+/// `text` with every legacy name in it replaced, e.g. `LCTL(KC_BSPACE)`
+fn rename_legacy(text: &str) -> String {
+    let mut renamed = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let len = match rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')) {
+            // a bracket, comma or space on its own
+            Some(0) => rest.chars().next().map_or(1, char::len_utf8),
+            Some(len) => len,
+            None => rest.len(),
+        };
+        let (word, after) = rest.split_at(len);
+        let word = LEGACY_NAMES
+            .iter()
+            .find(|(legacy, _)| *legacy == word)
+            .map_or(word, |(_, name)| name);
+        renamed.push_str(word);
+        rest = after;
+    }
+    renamed
+}
+
+/// A keycode given by its value
+fn from_code(code: u16, warnings: &mut Vec<String>) -> Keycode {
+    let keycode = Keycode::decode(code, &Constants::default());
+    // values in a range without a structure, unicode ones are fine as they are
+    if matches!(&keycode, Keycode::Raw(text) if text.starts_with("0x")) {
+        warnings.push(format!("unknown keycode {:#06x}", code));
+    }
+    keycode
+}
+
+// ["text", "hello"], ["tap", "KC_LCTRL", "KC_C"], ["delay", 100], ...
+fn parse_vial_action(action: &JsonValue, warnings: &mut Vec<String>) -> Option<MacroAction> {
+    let keys = |warnings: &mut Vec<String>| {
+        action
+            .members()
+            .skip(1)
+            .map(|key| parse_key(key, warnings))
+            .collect::<Vec<_>>()
+    };
+    Some(match action[0].as_str()? {
+        "text" => MacroAction::Text(action[1].as_str()?.to_string()),
+        "tap" => MacroAction::Tap(keys(warnings)),
+        "down" => MacroAction::Down(keys(warnings)),
+        "up" => MacroAction::Up(keys(warnings)),
+        "delay" => MacroAction::Delay(action[1].as_u32()?),
+        other => {
+            warnings.push(format!("unknown macro action `{}`", other));
+            return None;
+        }
+    })
+}
+
+// text with `{KC_A}` or `{KC_LCTL,KC_C}` for taps, `{+KC_LSFT}`/`{-KC_LSFT}` for press and
+// release, and `{100}` for a delay
+fn parse_via_macro(text: &str, warnings: &mut Vec<String>) -> Vec<MacroAction> {
+    let mut actions = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (plain, braced) = match rest.find('{') {
+            Some(open) => match rest[open..].find('}') {
+                Some(close) => (&rest[..open], Some(&rest[open + 1..open + close])),
+                None => (rest, None),
+            },
+            None => (rest, None),
+        };
+        if !plain.is_empty() {
+            actions.push(MacroAction::Text(plain.to_string()));
+        }
+        let Some(braced) = braced else {
+            break;
+        };
+        rest = &rest[plain.len() + braced.len() + 2..];
+
+        let keys = |names: &str, warnings: &mut Vec<String>| {
+            names
+                .split(',')
+                .map(|name| parse_key(&JsonValue::from(name.trim()), warnings))
+                .collect::<Vec<_>>()
+        };
+        actions.push(if let Ok(ms) = braced.trim().parse() {
+            MacroAction::Delay(ms)
+        } else if let Some(names) = braced.strip_prefix('+') {
+            MacroAction::Down(keys(names, warnings))
+        } else if let Some(names) = braced.strip_prefix('-') {
+            MacroAction::Up(keys(names, warnings))
+        } else {
+            MacroAction::Tap(keys(braced, warnings))
+        });
+    }
+    actions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_names() {
+        // layer and macro of a .vil saved by Vial 0.7
+        let vil = r#"{
+            "version": 1, "uid": 1,
+            "layout": [[["KC_LCTRL", "KC_LSHIFT", "KC_BSPACE", "KC_SCOLON", "KC_PGDOWN", "KC_TRNS",
+                         "LCTL(KC_BSPACE)", "LT(1,KC_SPACE)", "RESET", -1]]],
+            "macro": [[["tap", "KC_LCTRL", "KC_C"], ["text", "é"]]],
+            "tap_dance": [], "combo": []
+        }"#;
+        let matrix = (0..10).map(|col| Some((0, col))).collect::<Vec<_>>();
+        let mut warnings = Vec::new();
+        let backup =
+            backup_from_json(&json::parse(vil).unwrap(), Path::new("test.vil"), &matrix, 10, &mut warnings)
+                .unwrap();
+
+        let messages = warnings.iter().map(|w| w.message.as_str()).collect::<Vec<_>>();
+        assert!(messages.is_empty(), "{:?}", messages);
+        let keys = backup.layers[0].keys.iter().map(Keycode::to_string).collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                "KC_LEFT_CTRL",
+                "KC_LEFT_SHIFT",
+                "KC_BACKSPACE",
+                "KC_SEMICOLON",
+                "KC_PAGE_DOWN",
+                "KC_TRANSPARENT",
+                "LCTL(KC_BACKSPACE)",
+                "LT(1, KC_SPACE)",
+                "QK_BOOTLOADER",
+                "KC_NO",
+            ]
+        );
+        assert_eq!(backup.tables.lines().len(), 1);
+    }
+
+    #[test]
+    fn rename() {
+        assert_eq!(rename_legacy("LSFT_T(KC_BSLASH)"), "LSFT_T(KC_BACKSLASH)");
+        assert_eq!(rename_legacy("KC_BSPACE2 é"), "KC_BSPACE2 é");
+    }
+}
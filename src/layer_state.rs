@@ -0,0 +1,33 @@
+/// Which layers are on, as the firmware sees them: one bit per layer for up to 32 layers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayerState {
+    pub layers: u32,
+    pub default_layers: u32,
+}
+
+impl Default for LayerState {
+    /// Only the base layer, until the firmware says otherwise
+    fn default() -> Self {
+        LayerState {
+            layers: 0,
+            default_layers: 1,
+        }
+    }
+}
+
+impl LayerState {
+    /// Default layers count as active, like in QMK's layer lookup
+    pub fn is_active(&self, layer: usize) -> bool {
+        layer < 32 && (self.layers | self.default_layers) & (1 << layer) != 0
+    }
+
+    /// The layer on top, which gives the window its title
+    pub fn highest(&self) -> usize {
+        let mask = self.layers | self.default_layers;
+        if mask == 0 {
+            0
+        } else {
+            31 - mask.leading_zeros() as usize
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use json::JsonValue;
+
+use crate::config_file;
+use crate::hjson;
+use crate::keycodes;
+
+/// What to draw for one keycode
+#[derive(Debug, Clone, Default)]
+pub struct Legend {
+    pub label: Option<String>,
+    pub shifted: Option<String>,
+    pub icon: Option<String>,
+}
+
+/// Legends by canonical keycode name, or by the name used in the keymap for custom keycodes
+#[derive(Debug, Default)]
+pub struct LegendTable {
+    entries: HashMap<String, Legend>,
+}
+
+static TABLE: OnceLock<LegendTable> = OnceLock::new();
+
+const BUILTIN_LEGENDS: &str = include_str!("legends_builtin.hjson");
+
+/// Loads the built-in legends with the user's overrides on top, from `path` or else
+/// `legends.toml`/`legends.json` in the visualiser's config dir. Goes after `keycodes::init`.
+pub fn init(path: Option<&Path>) {
+    let mut table = builtin();
+    let path = path.map(Path::to_path_buf).or_else(|| config_file::find("legends"));
+    if let Some(path) = path {
+        match load_file(&path) {
+            Ok(overrides) => table.merge(&overrides),
+            Err(e) => eprintln!("warning: {}, using the built-in legends", e),
+        }
+    }
+    let _ = TABLE.set(table);
+}
+
+pub fn table() -> &'static LegendTable {
+    TABLE.get_or_init(builtin)
+}
+
+fn builtin() -> LegendTable {
+    let spec = hjson::parse(BUILTIN_LEGENDS).expect("built-in legend table is valid");
+    let mut table = LegendTable::default();
+    table.merge(&spec);
+    table
+}
+
+/// Reads an override file into the same shape as the built-in table
+fn load_file(path: &Path) -> Result<JsonValue, String> {
+    let value = config_file::read(path)?;
+    if !value["legends"].is_object() {
+        return Err(format!("{} has no `legends` table", path.display()));
+    }
+    Ok(value)
+}
+
+impl LegendTable {
+    /// Adds the entries of a `{ legends: { NAME: { label, shifted, icon } } }` document,
+    /// overriding fields of existing ones. A plain string is short for `{ label: ... }`.
+    fn merge(&mut self, spec: &JsonValue) {
+        for (name, entry) in spec["legends"].entries() {
+            let legend = self.entries.entry(canonical_name(name)).or_default();
+            if let Some(label) = entry.as_str() {
+                legend.label = Some(label.to_string());
+                continue;
+            }
+            let field = |key: &str| entry[key].as_str().map(str::to_string);
+            if let Some(label) = field("label") {
+                legend.label = Some(label);
+            }
+            if let Some(shifted) = field("shifted") {
+                legend.shifted = Some(shifted);
+            }
+            if let Some(icon) = field("icon") {
+                legend.icon = Some(icon);
+            }
+        }
+    }
+
+    /// Looks a legend up by keycode name or alias
+    pub fn get(&self, name: &str) -> Option<&Legend> {
+        self.entries.get(&canonical_name(name))
+    }
+}
+
+// aliases like KC_ESC share the entry of KC_ESCAPE
+fn canonical_name(name: &str) -> String {
+    keycodes::table()
+        .lookup(name)
+        .map(|spec| spec.key.clone())
+        .unwrap_or_else(|| name.to_string())
+}
@@ -1,151 +1,460 @@
-use std::io::{BufRead, BufReader};
+use std::io::BufRead;
 use std::num::NonZero;
-use std::process::{ChildStdout, Command, Stdio};
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use ab_glyph::{Font, FontArc, Glyph, PxScale, point};
+use ab_glyph::{FontArc, PxScale};
+use clap::Parser;
 use fontdb::Database;
 use softbuffer::{Context, Surface};
-use tiny_skia::{Color, Paint, Pixmap, Transform};
+use tiny_skia::{Color, FillRule, Paint, PathBuilder, Pixmap, Transform};
 use winit::application::ApplicationHandler;
 use winit::event::WindowEvent;
-use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
-use winit::platform::wayland::WindowAttributesExtWayland;
+use winit::event_loop::{ActiveEventLoop, ControlFlow, EventLoop, EventLoopProxy};
 use winit::window::{Window, WindowId};
 
-use crate::keymap_c_parser::Layer;
-
+use crate::cli::{Cli, Command};
+use crate::diagnostic::Diagnostic;
+use crate::keyboard::Keyboard;
+use crate::keymap_c_parser::{Keycode, Layer};
+use crate::keymap_vial_parser::{Backup, KeymapTables};
+use crate::console::{ConsoleEvent, ConsoleProtocol, ConsoleState};
+use crate::source::{FileSource, HidConsole, QmkConsole, StateSource};
+use crate::mods::Mods;
+use crate::text::{GlyphCache, draw_text};
+use crate::via::{HidrawTransport, Via};
+
+mod c_lexer;
+mod c_preprocessor;
+mod cli;
+mod config_file;
+mod console;
+mod diagnostic;
+mod hidraw;
+mod hjson;
+mod keyboard;
+mod keycode_values;
+mod keycodes;
 mod keymap_c_parser;
-
-const KEY_SPACING: f32 = 50.0;
-const KEY_WIDTH: f32 = 47.0;
+mod keymap_json_parser;
+mod keymap_vial_parser;
+mod layer_state;
+mod legends;
+mod mods;
+mod qmk_config;
+mod source;
+mod text;
+mod via;
+
+// everything on screen is measured in key units (1u), scaled to fit the window
+/// Logical pixels per unit the window opens with
+const DEFAULT_UNIT_SIZE: f32 = 50.0;
+/// Space between neighbouring keys
+const KEY_GAP: f32 = 0.06;
+const LEGEND_SIZE: f32 = 0.6;
+const LAYER_NAME_SIZE: f32 = 0.4;
+/// Strip below the keys holding the layer name and the status line under it
+const LAYER_STRIP_HEIGHT: f32 = 1.3;
+const TABLE_TEXT_SIZE: f32 = 0.3;
+const TABLE_LINE_HEIGHT: f32 = 0.45;
+/// Connection badge, in pixels as it has to stay readable on tiny windows
+const STATUS_SIZE: f32 = 14.0;
+
+/// Waits between attempts to reopen the console, doubling up to the maximum
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
 
 fn main() {
-    let folder = "/qmk_firmware/keyboards/macro";
-    let home = std::env::var("HOME").unwrap();
-    let path = format!("{}/{}", home, folder);
-    let keyboard_json = format!("{}/keyboard.json", path);
-    let keymap_c = format!("{}/keymaps/macro/keymap.c", path);
-
-    let json_string =
-        std::fs::read_to_string(&keyboard_json).expect("Failed to read keyboard.json");
-    let mut keyboard = json::parse(&json_string).expect("Failed to parse JSON");
-
-    let mut key_positions = keyboard
-        .remove("layouts")
-        .remove("LAYOUT_40_macro")
-        .remove("layout")
+    let cli = Cli::parse();
+    if let Err(e) = run(cli) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Diagnostic> {
+    let selection = cli.selection;
+    let config = qmk_config::load();
+    let qmk_home = selection
+        .qmk_home
+        .or_else(|| config.qmk_home())
+        .ok_or("can't locate qmk_firmware, pass --qmk-home or set QMK_HOME")?;
+    let userspace = config.userspace();
+    keycodes::init(&qmk_home);
+    legends::init(cli.legends.as_deref());
+    if let Some(Command::Keycode { keycodes }) = &cli.command {
+        print_keycodes(keycodes);
+        return Ok(());
+    }
+    // a keymap.json names its keyboard, so it's read before the keyboard is resolved
+    let mut warnings = Vec::new();
+    let given_json = match &selection.keymap_file {
+        Some(path) if keymap_json_parser::is_json(path) && !keymap_vial_parser::is_backup(path) => {
+            let parsed = keymap_json_parser::parse_json_keymap(path, &mut warnings);
+            Some(parsed?)
+        }
+        _ => None,
+    };
+    let keyboard_name = selection
+        .keyboard
+        .or_else(|| given_json.as_ref().and_then(|k| k.keyboard.clone()))
+        .or(config.keyboard.clone())
+        .ok_or(
+            "no keyboard selected, pass --keyboard <NAME> or run `qmk config user.keyboard=<NAME>`",
+        )?;
+    let keymap_name = selection
+        .keymap
+        .or(config.keymap.clone())
+        .unwrap_or_else(|| "default".to_string());
+
+    let keyboard = keyboard::resolve(&qmk_home, &keyboard_name)?;
+
+    if let Some(Command::Layouts) = cli.command {
+        for name in keyboard.layout_names() {
+            println!("{}", name);
+        }
+        for (alias, target) in keyboard.aliases() {
+            println!("{} -> {}", alias, target);
+        }
+        return Ok(());
+    }
+
+    let vid_pid = cli.device.as_deref().map(hidraw::parse_vid_pid).transpose()?;
+    let keymap_path = match selection.keymap_file {
+        Some(path) => Some(path),
+        None if selection.via => None,
+        None => Some(keyboard.find_keymap(&qmk_home, userspace.as_deref(), &keymap_name)?),
+    };
+    let mut tables = KeymapTables::default();
+    let parsed = match (given_json, keymap_path) {
+        (Some(json_keymap), _) => Ok((json_keymap.layout, json_keymap.layers)),
+        (None, None) => read_via(&keyboard, vid_pid, selection.layout.clone()),
+        (None, Some(path)) if keymap_vial_parser::is_backup(&path) => {
+            parse_backup(&keyboard, &path, selection.layout.clone(), &mut warnings).map(
+                |(layout, backup)| {
+                    tables = backup.tables;
+                    (layout, backup.layers)
+                },
+            )
+        }
+        (None, Some(path)) if keymap_json_parser::is_json(&path) => {
+            keymap_json_parser::parse_json_keymap(&path, &mut warnings)
+                .map(|json_keymap| (json_keymap.layout, json_keymap.layers))
+        }
+        (None, Some(path)) => {
+            let include_dirs =
+                keyboard.include_dirs(&path, &qmk_home, userspace.as_deref(), &keymap_name);
+            parse_keymap_c(&keyboard, &path, selection.layout.clone(), &include_dirs, &mut warnings)
+        }
+    };
+    for warning in &warnings {
+        eprintln!("{}", warning);
+    }
+    let (layout_name, mut keymap) = parsed?;
+    // --layout wins over the one a keymap.json names, in case that's missing from the keyboard
+    let layout_name = selection.layout.unwrap_or(layout_name);
+
+    let layout = keyboard
+        .layout(&layout_name)
+        .ok_or_else(|| format!("keyboard `{}` has no layout `{}`", keyboard.name, layout_name))?;
+    let mut key_positions = layout["layout"]
         .members()
-        .map(|k| {
-            let x = k["x"].as_f32().unwrap();
-            let y = k["y"].as_f32().unwrap();
-            println!("Key position: x={}, y={}", x, y);
-            KeyPosition { x, y }
+        .enumerate()
+        .map(|(i, key)| {
+            KeyPosition::from_json(key)
+                .map_err(|e| format!("key {} of layout `{}` {}", i, layout_name, e))
         })
-        .collect::<Vec<_>>();
+        .collect::<Result<Vec<_>, _>>()?;
+    for layer in &mut keymap {
+        if layer.keys.len() != key_positions.len() {
+            eprintln!(
+                "warning: layer {} has {} keys, layout `{}` has {}",
+                layer.name,
+                layer.keys.len(),
+                layout_name,
+                key_positions.len()
+            );
+            layer.keys.resize_with(key_positions.len(), || Keycode::NONE);
+        }
+    }
 
-    //get bounding box
-    let min_x = key_positions
+    //get bounding box, rotated keys can stick out past their unrotated position
+    let corners = key_positions
         .iter()
-        .map(|k| k.x)
-        .fold(f32::INFINITY, f32::min);
-    let min_y = key_positions
-        .iter()
-        .map(|k| k.y)
-        .fold(f32::INFINITY, f32::min);
-    let max_x = key_positions
+        .flat_map(KeyPosition::corners)
+        .collect::<Vec<_>>();
+    let min_x = corners.iter().map(|c| c.0).fold(f32::INFINITY, f32::min);
+    let min_y = corners.iter().map(|c| c.1).fold(f32::INFINITY, f32::min);
+    let max_x = corners
         .iter()
-        .map(|k| k.x)
+        .map(|c| c.0)
         .fold(f32::NEG_INFINITY, f32::max);
-    let max_y = key_positions
+    let max_y = corners
         .iter()
-        .map(|k| k.y)
+        .map(|c| c.1)
         .fold(f32::NEG_INFINITY, f32::max);
 
-    println!(
-        "min_x: {}, min_y: {}, max_x: {}, max_y: {}",
-        min_x, min_y, max_x, max_y
-    );
-
     for key in &mut key_positions {
         key.x -= min_x;
         key.y -= min_y;
+        key.rx -= min_x;
+        key.ry -= min_y;
     }
 
-    let width = (max_x - min_x + 1.0) * KEY_SPACING;
-    let height = (max_y - min_y + 1.0) * KEY_SPACING;
+    let mut board_size = (max_x - min_x, max_y - min_y);
+    let tables = tables.lines();
 
-    let keymap = keymap_c_parser::parse_c_source(&keymap_c);
+    if let Some(Command::Dump) = cli.command {
+        for layer in &keymap {
+            println!("{}:", layer.name);
+            for key in &layer.keys {
+                println!("    {}", key);
+            }
+        }
+        for line in &tables {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
 
-    let mut child = Command::new("qmk")
-        .arg("console")
-        .stdout(Stdio::piped()) // capture stdout
-        .stderr(Stdio::piped()) // optional: capture stderr too
-        .spawn().unwrap(); // spawn the process
+    // the layer name goes below the keys, then tap dances, combos and macros
+    board_size.1 += LAYER_STRIP_HEIGHT + tables.len() as f32 * TABLE_LINE_HEIGHT;
 
-    let stdout = child.stdout.take().expect("Failed to capture stdout");
-    let reader = BufReader::new(stdout);
+    let protocol = ConsoleProtocol::load(cli.console_config.as_deref())?;
 
+    // read the console ourselves when we can, `qmk console` is the fallback
+    let source: Box<dyn StateSource> = if let Some(path) = cli.console_path {
+        Box::new(FileSource { path })
+    } else if let Some(spec) = &cli.source {
+        source::parse(spec)?
+    } else if vid_pid.is_some() || !hidraw::find_consoles().is_empty() {
+        Box::new(HidConsole { vid_pid })
+    } else {
+        Box::new(QmkConsole::default())
+    };
+    let child = source.child_process();
 
-    render_main(key_positions, keymap, reader, (width as usize, height as usize));
+    render_main(key_positions, keymap, tables, source, protocol, board_size);
 
-    child.kill().unwrap();
+    if let Some(child) = child {
+        child.kill();
+    }
+    Ok(())
 }
 
-fn render_main(key_positions: Vec<KeyPosition>, layers: Vec<Layer>, reader: BufReader<ChildStdout>, size: (usize, usize)) {
-    let event_loop = EventLoop::new().unwrap();
+/// Prints the name of every value and the value of every name, layers are numbers
+fn print_keycodes(keycodes: &[String]) {
+    let constants = keymap_c_parser::Constants::default();
+    for text in keycodes {
+        let mut warnings = Vec::new();
+        let keycode = match c_preprocessor::parse_int(text) {
+            Some(code) => match u16::try_from(code) {
+                Ok(code) => Keycode::decode(code, &constants),
+                Err(_) => {
+                    eprintln!("warning: {} is out of the 16-bit range", text);
+                    continue;
+                }
+            },
+            None => Keycode::parse_text(text, &constants, &mut warnings),
+        };
+        for warning in warnings {
+            eprintln!("warning: {}", warning);
+        }
+        match keycode.encode(&constants) {
+            Some(code) => println!("{:#06x}  {}", code, keycode),
+            None => println!("{:6}  {}", "-", keycode),
+        }
+    }
+}
 
-    // ControlFlow::Poll continuously runs the event loop, even if the OS hasn't
-    // dispatched any events. This is ideal for games and similar applications.
-    event_loop.set_control_flow(ControlFlow::Poll);
+/// The switch matrix position of every key of a layout, `None` for keys without one
+type Matrix = Vec<Option<(u8, u8)>>;
+
+/// `layout`, or else the keyboard's largest layout, and its matrix, for keymaps stored by
+/// matrix position
+fn layout_matrix(keyboard: &Keyboard, layout: Option<String>) -> Result<(String, Matrix), Diagnostic> {
+    let layout = layout
+        .or_else(|| keyboard.largest_layout())
+        .ok_or_else(|| format!("keyboard `{}` has no layouts", keyboard.name))?;
+    let keys = keyboard
+        .layout(&layout)
+        .ok_or_else(|| format!("keyboard `{}` has no layout `{}`", keyboard.name, layout))?;
+    let matrix = keys["layout"]
+        .members()
+        .map(|key| Some((key["matrix"][0].as_u8()?, key["matrix"][1].as_u8()?)))
+        .collect();
+    Ok((layout, matrix))
+}
+
+/// Reads a Vial or VIA backup
+fn parse_backup(
+    keyboard: &Keyboard,
+    path: &Path,
+    layout: Option<String>,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<(String, Backup), Diagnostic> {
+    let (layout, matrix) = layout_matrix(keyboard, layout)?;
+    let backup = keymap_vial_parser::parse_backup(path, &matrix, keyboard.matrix_size().1, warnings)?;
+    Ok((layout, backup))
+}
 
-    // ControlFlow::Wait pauses the event loop if no events are available to process.
-    // This is ideal for non-game applications that only update in response to user
-    // input, and uses significantly less power/CPU time than ControlFlow::Poll.
+/// Reads the dynamic keymap out of the keyboard over VIA
+fn read_via(
+    keyboard: &Keyboard,
+    vid_pid: Option<(u16, u16)>,
+    layout: Option<String>,
+) -> Result<(String, Vec<Layer>), Diagnostic> {
+    let (layout, matrix) = layout_matrix(keyboard, layout)?;
+    let (rows, cols) = keyboard.matrix_size();
+    if rows == 0 || cols == 0 {
+        return Err(format!("keyboard `{}`'s matrix size is unknown", keyboard.name).into());
+    }
+    let device = hidraw::select_raw_hid(vid_pid)?;
+    eprintln!("reading the keymap of {}", device);
+    let mut via = Via::new(HidrawTransport::open(&device.path)?);
+    let layers = via
+        .read_keymap(rows, cols, &matrix)
+        .map_err(|e| format!("{}: {}", device.path.display(), e))?;
+    Ok((layout, layers))
+}
+
+/// Parses a keymap.c, working out which of the keyboard's layouts it uses
+fn parse_keymap_c(
+    keyboard: &Keyboard,
+    keymap_c: &Path,
+    layout: Option<String>,
+    include_dirs: &[PathBuf],
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<(String, Vec<Layer>), Diagnostic> {
+    // the macro the keymap calls may be an alias of the layout holding the positions
+    let layout_candidates = match layout {
+        Some(name) if keyboard.layout(&name).is_some() => vec![name],
+        Some(name) => {
+            return Err(format!(
+                "keyboard `{}` has no layout `{}`, available: {}",
+                keyboard.name,
+                name,
+                keyboard.layout_names().join(", ")
+            )
+            .into());
+        }
+        None => {
+            let mut names = keyboard.layout_names();
+            names.extend(keyboard.aliases().into_iter().map(|(alias, _)| alias));
+            names
+        }
+    };
+
+    let Some(parsed) =
+        keymap_c_parser::parse_c_source(keymap_c, &layout_candidates, include_dirs, warnings)?
+    else {
+        return Err(format!(
+            "{} doesn't call any layout of `{}`, pick one with --layout: {}",
+            keymap_c.display(),
+            keyboard.name,
+            layout_candidates.join(", ")
+        )
+        .into());
+    };
+    Ok(parsed)
+}
+
+fn render_main(key_positions: Vec<KeyPosition>, layers: Vec<Layer>, tables: Vec<String>, source: Box<dyn StateSource>, protocol: ConsoleProtocol, board_size: (f32, f32)) {
+    let event_loop = EventLoop::<AppEvent>::with_user_event().build().unwrap();
+
+    // nothing changes between console updates, so only wake up for events
     event_loop.set_control_flow(ControlFlow::Wait);
 
     let font = load_font();
 
-    let active_layers = Arc::new(Mutex::new([false; 8]));
-
-    let _handle = std::thread::spawn({
-        let active_layers = active_layers.clone();
-        move || {
-            read_console(reader, active_layers);
-        }
+    let proxy = event_loop.create_proxy();
+    let _handle = std::thread::spawn(move || {
+        read_console(source, protocol, proxy);
     });
 
     let mut app = App {
         key_positions,
-        size,
+        board_size,
         layers,
-        font,
+        tables,
+        glyphs: GlyphCache::new(font),
         window: None,
-        current_layer: active_layers,
+        surface: None,
+        state: ConsoleState::default(),
+        status: ConnectionStatus::Waiting,
     };
     let _ = event_loop.run_app(&mut app);
 }
 
+/// Sent from the console reader to the event loop
+#[derive(Debug)]
+pub enum AppEvent {
+    Console(ConsoleEvent),
+    Status(ConnectionStatus),
+}
+
+/// How reading the console is going, shown as a badge in the corner
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Waiting,
+    Connected,
+    Disconnected(String),
+    /// A source that isn't reopened ran out, e.g. a recorded log, its last state stays up
+    Ended,
+}
+
 struct App {
-    size: (usize, usize),
-    window: Option<Window>,
+    board_size: (f32, f32), // in units
+    window: Option<Arc<Window>>,
+    surface: Option<Surface<Arc<Window>, Arc<Window>>>,
     key_positions: Vec<KeyPosition>,
     layers: Vec<Layer>,
-    current_layer: Arc<Mutex<[bool; 8]>>,
-    font: FontArc,
+    tables: Vec<String>, // lines listed below the keys
+    state: ConsoleState,
+    status: ConnectionStatus,
+    glyphs: GlyphCache,
 }
 
-impl ApplicationHandler for App {
+impl ApplicationHandler<AppEvent> for App {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        println!("size: {:?}", self.size);
         let mut attrs = Window::default_attributes();
-        attrs = attrs.with_resizable(false);
+        attrs = attrs.with_resizable(true);
+        // logical pixels, so the keyboard starts out the same size on high DPI screens
         attrs = attrs.with_inner_size(winit::dpi::LogicalSize::new(
-            self.size.0 as u32,
-            self.size.1 as u32,
+            self.board_size.0 * DEFAULT_UNIT_SIZE,
+            self.board_size.1 * DEFAULT_UNIT_SIZE,
         ));
         attrs = attrs.with_title("Keyboard_visualizer");
-        self.window = Some(event_loop.create_window(attrs).unwrap());
+        let window = Arc::new(event_loop.create_window(attrs).unwrap());
+        let context = Context::new(window.clone()).unwrap();
+        let mut surface = Surface::new(&context, window.clone()).unwrap();
+        let size = window.inner_size();
+        if let (Some(width), Some(height)) = (NonZero::new(size.width), NonZero::new(size.height)) {
+            surface.resize(width, height).unwrap();
+        }
+        self.surface = Some(surface);
+        self.window = Some(window);
+    }
+
+    fn user_event(&mut self, _event_loop: &ActiveEventLoop, event: AppEvent) {
+        match event {
+            AppEvent::Console(event) => {
+                if self.state.apply(event) {
+                    self.request_redraw();
+                }
+            }
+            AppEvent::Status(status) => {
+                // whatever the keyboard said before is stale now
+                if matches!(status, ConnectionStatus::Disconnected(_)) {
+                    self.state = ConsoleState::default();
+                }
+                if status != self.status {
+                    self.status = status;
+                    self.request_redraw();
+                }
+            }
+        }
     }
 
     fn window_event(&mut self, event_loop: &ActiveEventLoop, _id: WindowId, event: WindowEvent) {
@@ -154,154 +463,255 @@ impl ApplicationHandler for App {
                 println!("The close button was pressed; stopping");
                 event_loop.exit();
             }
+            WindowEvent::ScaleFactorChanged { .. } => self.request_redraw(),
+            WindowEvent::Resized(size) => {
+                if let (Some(surface), Some(width), Some(height)) = (
+                    self.surface.as_mut(),
+                    NonZero::new(size.width),
+                    NonZero::new(size.height),
+                ) {
+                    surface.resize(width, height).unwrap();
+                }
+                self.request_redraw();
+            }
             WindowEvent::RedrawRequested => {
-                let window = self.window.as_ref().unwrap();
-
-                let size = window.inner_size();
+                let size = self.window.as_ref().unwrap().inner_size();
                 let width = size.width;
                 let height = size.height;
-
-                let context = Context::new(window).unwrap();
-                let mut surface = Surface::new(&context, window).unwrap();
-                surface
-                    .resize(NonZero::new(width).unwrap(), NonZero::new(height).unwrap())
-                    .unwrap();
+                if width == 0 || height == 0 {
+                    return;
+                }
 
                 let mut pixmap = Pixmap::new(width, height).unwrap();
                 let mut paint = Paint::default();
 
                 paint.set_color_rgba8(100, 100, 100, 255);
+                let mut pressed_paint = Paint::default();
+                pressed_paint.set_color_rgba8(210, 120, 40, 255);
                 pixmap.fill(Color::from_rgba8(30, 30, 30, 255));
-                let key_scale = PxScale::from(30.0);
+                let (unit, board) = self.viewport(width, height);
+                let key_scale = PxScale::from(LEGEND_SIZE * unit);
 
                 let max_layer = self.layers.len();
 
                 for key in self.key_positions.iter().enumerate() {
+                    let transform = board.pre_concat(key.1.transform(unit));
                     let rect = tiny_skia::Rect::from_xywh(
-                        key.1.x * KEY_SPACING,
-                        key.1.y * KEY_SPACING,
-                        KEY_WIDTH,
-                        KEY_WIDTH,
+                        key.1.x * unit,
+                        key.1.y * unit,
+                        (key.1.w - KEY_GAP) * unit,
+                        (key.1.h - KEY_GAP) * unit,
+                    )
+                    .unwrap();
+                    let pressed = key.1.matrix.is_some_and(|m| self.state.pressed.contains(&m));
+                    pixmap.fill_rect(rect, if pressed { &pressed_paint } else { &paint }, transform, None);
+
+                    // legends stay upright, centred on the (possibly rotated) key
+                    let mut center = tiny_skia::Point::from_xy(
+                        (key.1.x + (key.1.w - KEY_GAP) / 2.0) * unit,
+                        (key.1.y + (key.1.h - KEY_GAP) / 2.0) * unit,
+                    );
+                    transform.map_point(&mut center);
+                    let legend_width = (key.1.w - KEY_GAP) * unit;
+                    let legend_height = (key.1.h - KEY_GAP) * unit;
+                    let legend_area = tiny_skia::Rect::from_xywh(
+                        center.x - legend_width / 2.0,
+                        center.y - legend_height / 2.0,
+                        legend_width,
+                        legend_height,
                     )
                     .unwrap();
-                    pixmap.fill_rect(rect, &paint, tiny_skia::Transform::identity(), None);
-                    
-                    for i in (0..8.min(max_layer)).rev() {
-                        if !self.current_layer.lock().unwrap()[i] {
+
+                    for i in (0..max_layer).rev() {
+                        if !self.state.layers.is_active(i) {
                             continue;
                         }
-                        let key_ = &self.layers[i].keys[key.0];
-                        let res = key_.render(key.1.x * KEY_SPACING, key.1.y * KEY_SPACING, &mut pixmap, &self.font.clone(), key_scale, Color::from_rgba8(255, 255, 255, 255));
+                        let Some(key_) = self.layers[i].keys.get(key.0) else {
+                            continue;
+                        };
+                        let res = key_.render(legend_area, &mut pixmap, &mut self.glyphs, key_scale, Color::from_rgba8(255, 255, 255, 255));
                         if res {
                             break;
                         }
                     }
                 }
 
-                let layer_scale = PxScale::from(20.0);
+                let layer_scale = PxScale::from(LAYER_NAME_SIZE * unit);
+
+                let tables_top = self.board_size.1 - self.tables.len() as f32 * TABLE_LINE_HEIGHT;
+                let layer = self.layers.get(self.state.layers.highest().min(max_layer.saturating_sub(1)));
+                let mut position = tiny_skia::Point::from_xy(
+                    0.0,
+                    (tables_top - LAYER_STRIP_HEIGHT + KEY_GAP * 2.0) * unit,
+                );
+                board.map_point(&mut position);
+                if let Some(layer) = layer {
+                    draw_text(
+                        &mut pixmap,
+                        &layer.name,
+                        &mut self.glyphs,
+                        layer_scale,
+                        position.x,
+                        position.y,
+                        Color::from_rgba8(255, 255, 255, 255),
+                    );
+                }
 
-                let text = &self.layers[self.current_layer.lock().unwrap().iter().enumerate().rev().find(|e| *e.1).map_or(0, |e| e.0).min(max_layer)].name;
+                // modifiers, caps word and custom status text below the layer name
+                let mut status = Vec::new();
+                if self.state.mods != Mods::default() {
+                    status.push(self.state.mods.symbols());
+                }
+                if self.state.caps_word {
+                    status.push("CAPS".to_string());
+                }
+                status.extend(self.state.label.clone());
                 draw_text(
                     &mut pixmap,
-                    text,
-                    &self.font,
+                    &status.join("  "),
+                    &mut self.glyphs,
                     layer_scale,
-                    320.0,
-                    80.0,
-                    Color::from_rgba8(255, 255, 255, 255),
+                    position.x,
+                    position.y + LAYER_NAME_SIZE * 1.5 * unit,
+                    Color::from_rgba8(200, 200, 200, 255),
                 );
 
+                for (i, line) in self.tables.iter().enumerate() {
+                    let mut position = tiny_skia::Point::from_xy(
+                        0.0,
+                        (tables_top + i as f32 * TABLE_LINE_HEIGHT) * unit,
+                    );
+                    board.map_point(&mut position);
+                    draw_text(
+                        &mut pixmap,
+                        line,
+                        &mut self.glyphs,
+                        PxScale::from(TABLE_TEXT_SIZE * unit),
+                        position.x,
+                        position.y,
+                        Color::from_rgba8(200, 200, 200, 255),
+                    );
+                }
+
+                self.draw_status(&mut pixmap);
+
                 // Copy pixmap data to window surface
+                let surface = self.surface.as_mut().unwrap();
                 let mut buffer = surface.buffer_mut().unwrap();
-                let buf = buffer.as_mut();
-                let data = pixmap
-                    .data()
-                    .chunks_exact(4)
-                    .map(|px| {
-                        ((px[3] as u32) << 24)
-                            | ((px[2] as u32) << 16)
-                            | ((px[1] as u32) << 8)
-                            | (px[0] as u32)
-                    })
-                    .collect::<Vec<_>>();
-                for (dst, src) in buf.chunks_exact_mut(4).zip(data.chunks_exact(4)) {
-                    dst.copy_from_slice(src);
+                for (dst, px) in buffer.iter_mut().zip(pixmap.pixels()) {
+                    *dst = ((px.red() as u32) << 16) | ((px.green() as u32) << 8) | (px.blue() as u32);
                 }
                 buffer.present().unwrap();
-
-                self.window.as_ref().unwrap().request_redraw();
             }
             _ => (),
         }
     }
 }
 
-pub fn read_console(mut reader: BufReader<ChildStdout>, active_layers: Arc<Mutex<[bool; 8]>>) {
-    active_layers.lock().unwrap()[0] = true; // always show base layer
-    let mut buf = String::new();
-    while let Ok(chars_read) = reader.read_line(&mut buf) {
-        if chars_read == 0 {
-            break; // EOF reached
-        }
-        if !buf.contains("LAYERS:") {
-            buf.clear();
-            continue;
+impl App {
+    /// Pixels per unit for a window of this size, and the transform that centres the board
+    /// in it. The board keeps its aspect ratio, the remaining space is split evenly.
+    fn viewport(&self, width: u32, height: u32) -> (f32, Transform) {
+        let unit = (width as f32 / self.board_size.0).min(height as f32 / self.board_size.1);
+        let x = (width as f32 - self.board_size.0 * unit) / 2.0;
+        let y = (height as f32 - self.board_size.1 * unit) / 2.0;
+        (unit, Transform::from_translate(x, y))
+    }
+
+    /// A coloured dot and the connection status in the top left corner
+    fn draw_status(&mut self, pixmap: &mut Pixmap) {
+        let (color, text) = match &self.status {
+            ConnectionStatus::Waiting => (Color::from_rgba8(230, 190, 40, 255), "waiting".to_string()),
+            ConnectionStatus::Connected => (Color::from_rgba8(80, 200, 80, 255), "connected".to_string()),
+            ConnectionStatus::Disconnected(reason) => {
+                (Color::from_rgba8(220, 60, 60, 255), format!("disconnected: {}", reason))
+            }
+            ConnectionStatus::Ended => (Color::from_rgba8(140, 140, 140, 255), "ended".to_string()),
+        };
+        let scale = self.window.as_ref().map_or(1.0, |w| w.scale_factor() as f32);
+        let size = STATUS_SIZE * scale;
+        let mut paint = Paint::default();
+        paint.set_color(color);
+        paint.anti_alias = true;
+        if let Some(dot) = PathBuilder::from_circle(size, size, size * 0.35) {
+            pixmap.fill_path(&dot, &paint, FillRule::Winding, Transform::identity(), None);
         }
+        draw_text(
+            pixmap,
+            &text,
+            &mut self.glyphs,
+            PxScale::from(size),
+            size * 1.6,
+            size * 0.4,
+            Color::from_rgba8(200, 200, 200, 255),
+        );
+    }
 
-        let layer_str = buf.trim().split("LAYERS:").nth(1).unwrap().trim();
-        for i in 0..8 {
-            active_layers.lock().unwrap()[i] = layer_str.chars().nth(i).unwrap() == '1';
+    fn request_redraw(&self) {
+        if let Some(window) = &self.window {
+            window.request_redraw();
         }
-        active_layers.lock().unwrap()[0] = true; // always show base layer
-        buf.clear();
-        
     }
 }
 
-pub fn draw_text(
-    pixmap: &mut Pixmap,
-    text: &str,
-    font: &FontArc,
-    scale: PxScale,
-    x: f32,
-    y: f32,
-    color: Color,
-) {
-    let mut transform = Transform::identity();
-    transform = transform.pre_translate(x, y);
-    let mut paint = Paint::default();
-    paint.set_color(color);
-
-    for ch in text.chars() {
-        let glyph_id = font.glyph_id(ch);
-        let glyph = Glyph {
-            id: glyph_id,
-            scale,
-            position: point(0.0, 0.0),
-        };
-        let advance = font.h_advance_unscaled(glyph_id);
-        let advance_scaled = advance * scale.x / font.units_per_em().unwrap_or(1.0);
-
-        let glyph = font.outline_glyph(glyph);
-        let Some(glyph) = glyph else {
-            transform = transform.pre_translate(advance_scaled, 0.0);
-            continue;
+/// Follows the console and forwards whatever the protocol recognises to the event loop.
+/// When the source ends or fails it's opened again, waiting longer after every failure, unless
+/// it can't give more lines.
+pub fn read_console(mut source: Box<dyn StateSource>, protocol: ConsoleProtocol, proxy: EventLoopProxy<AppEvent>) {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        if proxy.send_event(AppEvent::Status(ConnectionStatus::Waiting)).is_err() {
+            return; // the window is gone
+        }
+        let reason = match source.open() {
+            Ok(mut reader) => {
+                if proxy.send_event(AppEvent::Status(ConnectionStatus::Connected)).is_err() {
+                    return;
+                }
+                let mut buf = String::new();
+                loop {
+                    buf.clear();
+                    match reader.read_line(&mut buf) {
+                        Ok(0) => break None,
+                        Ok(_) => {}
+                        Err(e) => break Some(e.to_string()),
+                    }
+                    // it's working, so the next failure is a fresh one
+                    backoff = MIN_BACKOFF;
+                    for event in protocol.parse_line(buf.trim_end()) {
+                        if proxy.send_event(AppEvent::Console(event)).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            Err(e) => Some(e),
         };
 
-        glyph.draw(|px, py, c| {
-            let alpha = (c * 255.0) as u8;
-            let color = Color::from_rgba8(255, 255, 255, alpha);
-            paint.set_color(color);
-            let x = px as f32
-                + font.h_side_bearing_unscaled(glyph_id) * scale.x
-                    / font.units_per_em().unwrap_or(1.0);
-            let y = py as f32;
-            let rect = tiny_skia::Rect::from_xywh(x, y, 1.0, 1.0).unwrap();
-            pixmap.fill_rect(rect, &paint, transform, None);
-        });
-        let advance = font.h_advance_unscaled(glyph_id);
-        let advance_scaled = advance * scale.x / font.units_per_em().unwrap_or(1.0);
-        transform = transform.pre_translate(advance_scaled, 0.0);
+        if !source.reconnects() {
+            let status = match reason {
+                Some(reason) => {
+                    eprintln!("{}: {}", source.describe(), reason);
+                    ConnectionStatus::Disconnected(reason)
+                }
+                None => ConnectionStatus::Ended,
+            };
+            let _ = proxy.send_event(AppEvent::Status(status));
+            return;
+        }
+        let reason = reason.unwrap_or_else(|| "end of stream".to_string());
+        let status = ConnectionStatus::Disconnected(reason.clone());
+        if proxy.send_event(AppEvent::Status(status)).is_err() {
+            return;
+        }
+        eprintln!(
+            "{}: {}, retrying in {:.1}s",
+            source.describe(),
+            reason,
+            backoff.as_secs_f32()
+        );
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
     }
 }
 
@@ -330,8 +740,52 @@ fn load_font() -> FontArc {
     }
 }
 
+/// A key of a layout in key units, rotated `r` degrees clockwise around (`rx`, `ry`)
 #[derive(Debug)]
 struct KeyPosition {
     x: f32,
     y: f32,
+    w: f32,
+    h: f32,
+    r: f32,
+    rx: f32,
+    ry: f32,
+    matrix: Option<(u8, u8)>, // row, col
+}
+
+impl KeyPosition {
+    fn from_json(key: &json::JsonValue) -> Result<Self, String> {
+        Ok(KeyPosition {
+            x: key["x"].as_f32().ok_or("has no `x`")?,
+            y: key["y"].as_f32().ok_or("has no `y`")?,
+            w: key["w"].as_f32().unwrap_or(1.0),
+            h: key["h"].as_f32().unwrap_or(1.0),
+            r: key["r"].as_f32().unwrap_or(0.0),
+            rx: key["rx"].as_f32().unwrap_or(0.0),
+            ry: key["ry"].as_f32().unwrap_or(0.0),
+            matrix: match (key["matrix"][0].as_u8(), key["matrix"][1].as_u8()) {
+                (Some(row), Some(col)) => Some((row, col)),
+                _ => None,
+            },
+        })
+    }
+
+    fn corners(&self) -> [(f32, f32); 4] {
+        let (sin, cos) = self.r.to_radians().sin_cos();
+        [
+            (self.x, self.y),
+            (self.x + self.w, self.y),
+            (self.x, self.y + self.h),
+            (self.x + self.w, self.y + self.h),
+        ]
+        .map(|(x, y)| {
+            let (dx, dy) = (x - self.rx, y - self.ry);
+            (self.rx + dx * cos - dy * sin, self.ry + dx * sin + dy * cos)
+        })
+    }
+
+    /// Rotation of the key, with `unit` pixels per unit
+    fn transform(&self, unit: f32) -> Transform {
+        Transform::from_rotate_at(self.r, self.rx * unit, self.ry * unit)
+    }
 }
@@ -0,0 +1,179 @@
+/// A set of modifiers in HID order: bits 0-3 are left Ctrl/Shift/Alt/GUI, bits 4-7 the right ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Mods(pub u8);
+
+impl Mods {
+    pub const LCTL: Mods = Mods(0x01);
+    pub const LSFT: Mods = Mods(0x02);
+    pub const LALT: Mods = Mods(0x04);
+    pub const LGUI: Mods = Mods(0x08);
+    pub const RCTL: Mods = Mods(0x10);
+    pub const RSFT: Mods = Mods(0x20);
+    pub const RALT: Mods = Mods(0x40);
+    pub const RGUI: Mods = Mods(0x80);
+
+    pub fn union(self, other: Mods) -> Mods {
+        Mods(self.0 | other.0)
+    }
+
+    pub fn is_shift_only(self) -> bool {
+        self == Self::LSFT || self == Self::RSFT
+    }
+
+    /// From QMK's 5-bit mod format used by `MOD_*` and in mod-tap keycodes: Ctrl, Shift, Alt,
+    /// GUI and a fifth bit that makes all of them right-hand mods
+    pub fn from_qmk_bits(bits: u8) -> Mods {
+        let mods = bits & 0x0F;
+        if bits & 0x10 != 0 {
+            Mods(mods << 4)
+        } else {
+            Mods(mods)
+        }
+    }
+
+    /// The 5-bit format back, `None` when left and right-hand mods are mixed as that can't be
+    /// expressed in it
+    pub fn to_qmk_bits(self) -> Option<u8> {
+        match (self.0 & 0x0F, self.0 >> 4) {
+            (left, 0) => Some(left),
+            (0, right) => Some(0x10 | right),
+            _ => None,
+        }
+    }
+
+    /// `MOD_LCTL`, `MOD_MASK_CS` and friends
+    pub fn from_mask_name(name: &str) -> Option<Mods> {
+        // the masks cover both hands, e.g. MOD_MASK_CS
+        if let Some(letters) = name.strip_prefix("MOD_MASK_") {
+            let letters = match letters {
+                "CTRL" => "C",
+                "SHIFT" => "S",
+                "ALT" => "A",
+                "GUI" => "G",
+                _ => letters,
+            };
+            let mut mods = Mods::default();
+            for c in letters.chars() {
+                mods = mods.union(match c {
+                    'C' => Mods(0x11),
+                    'S' => Mods(0x22),
+                    'A' => Mods(0x44),
+                    'G' => Mods(0x88),
+                    _ => return None,
+                });
+            }
+            return Some(mods);
+        }
+        let mods = match name.strip_prefix("MOD_")? {
+            "LCTL" => Self::LCTL,
+            "LSFT" => Self::LSFT,
+            "LALT" => Self::LALT,
+            "LGUI" => Self::LGUI,
+            "RCTL" => Self::RCTL,
+            "RSFT" => Self::RSFT,
+            "RALT" => Self::RALT,
+            "RGUI" => Self::RGUI,
+            "HYPR" => Mods(0x0F),
+            "MEH" => Mods(0x07),
+            _ => return None,
+        };
+        Some(mods)
+    }
+
+    /// Modifier wrappers like `LCTL(kc)`, also the base of the `_T` mod-tap names
+    pub fn from_wrapper_name(name: &str) -> Option<Mods> {
+        WRAPPERS
+            .iter()
+            .find(|(names, _)| names.contains(&name))
+            .map(|(_, mods)| *mods)
+    }
+
+    /// Mod-tap shorthands like `LSFT_T(kc)` or `ALL_T(kc)`
+    pub fn from_mod_tap_name(name: &str) -> Option<Mods> {
+        let base = name.strip_suffix("_T")?;
+        match base {
+            "CTL" => Some(Self::LCTL),
+            "SFT" => Some(Self::LSFT),
+            "ALT" | "OPT" => Some(Self::LALT),
+            "GUI" | "CMD" | "WIN" => Some(Self::LGUI),
+            "ALL" => Some(Mods(0x0F)),
+            "C_S" => Some(Mods(0x03)),
+            _ => Self::from_wrapper_name(base),
+        }
+    }
+
+    /// The canonical wrapper for exactly these mods, if QMK has one
+    pub fn wrapper_name(self) -> Option<&'static str> {
+        WRAPPERS
+            .iter()
+            .find(|(_, mods)| *mods == self)
+            .map(|(names, _)| names[0])
+    }
+
+    /// Compact symbols like `⌃⇧`, prefixed with `R` when only right-hand mods are held
+    pub fn symbols(self) -> String {
+        let both = (self.0 | self.0 >> 4) & 0x0F;
+        let mut text = String::new();
+        if self.0 & 0x0F == 0 && self.0 != 0 {
+            text.push('R');
+        }
+        for (bit, symbol) in [(0x01, '⌃'), (0x04, '⌥'), (0x02, '⇧'), (0x08, '⌘')] {
+            if both & bit != 0 {
+                text.push(symbol);
+            }
+        }
+        text
+    }
+}
+
+impl std::fmt::Display for Mods {
+    /// As an ORed `MOD_*` expression
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = ["LCTL", "LSFT", "LALT", "LGUI", "RCTL", "RSFT", "RALT", "RGUI"];
+        let parts = names
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| self.0 & (1 << bit) != 0)
+            .map(|(_, name)| format!("MOD_{}", name))
+            .collect::<Vec<_>>();
+        if parts.is_empty() {
+            write!(f, "0")
+        } else {
+            write!(f, "{}", parts.join(" | "))
+        }
+    }
+}
+
+// first name is the canonical one
+const WRAPPERS: &[(&[&str], Mods)] = &[
+    (&["LCTL", "C"], Mods::LCTL),
+    (&["LSFT", "S"], Mods::LSFT),
+    (&["LALT", "A", "LOPT"], Mods::LALT),
+    (&["LGUI", "G", "LCMD", "LWIN"], Mods::LGUI),
+    (&["RCTL"], Mods::RCTL),
+    (&["RSFT"], Mods::RSFT),
+    (&["RALT", "ROPT", "ALGR"], Mods::RALT),
+    (&["RGUI", "RCMD", "RWIN"], Mods::RGUI),
+    (&["HYPR"], Mods(0x0F)),
+    (&["MEH"], Mods(0x07)),
+    (&["LCS"], Mods(0x03)),
+    (&["LCA"], Mods(0x05)),
+    (&["LCG"], Mods(0x09)),
+    (&["LSA"], Mods(0x06)),
+    (&["LSG", "SGUI", "SCMD", "SWIN"], Mods(0x0A)),
+    (&["LAG"], Mods(0x0C)),
+    (&["LCSG"], Mods(0x0B)),
+    (&["LCAG"], Mods(0x0D)),
+    (&["LSAG"], Mods(0x0E)),
+    (&["RCS"], Mods(0x30)),
+    (&["RCA"], Mods(0x50)),
+    (&["RCG"], Mods(0x90)),
+    (&["RSA", "SAGR"], Mods(0x60)),
+    (&["RSG"], Mods(0xA0)),
+    (&["RAG"], Mods(0xC0)),
+    (&["RCSG"], Mods(0xB0)),
+    (&["RCAG"], Mods(0xD0)),
+    (&["RSAG"], Mods(0xE0)),
+    (&["RHYPR"], Mods(0xF0)),
+    (&["RMEH"], Mods(0x70)),
+];
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// The subset of the qmk cli's `qmk.ini` the visualiser cares about
+#[derive(Debug, Default)]
+pub struct QmkConfig {
+    pub keyboard: Option<String>,
+    pub keymap: Option<String>,
+    pub qmk_home: Option<PathBuf>,
+    pub overlay_dir: Option<PathBuf>,
+}
+
+pub fn load() -> QmkConfig {
+    let Some(path) = config_path() else {
+        return QmkConfig::default();
+    };
+    let Ok(text) = std::fs::read_to_string(&path) else {
+        return QmkConfig::default();
+    };
+    let values = parse_ini(&text);
+    let get = |key: &str| values.get(key).cloned();
+
+    QmkConfig {
+        keyboard: get("user.keyboard"),
+        keymap: get("user.keymap"),
+        qmk_home: get("user.qmk_home").map(|p| expand_home(&p)),
+        overlay_dir: get("user.overlay_dir").map(|p| expand_home(&p)),
+    }
+}
+
+impl QmkConfig {
+    /// `QMK_HOME` wins over `user.qmk_home`, falling back to `~/qmk_firmware` like the qmk cli
+    pub fn qmk_home(&self) -> Option<PathBuf> {
+        if let Some(home) = std::env::var_os("QMK_HOME").filter(|h| !h.is_empty()) {
+            return Some(PathBuf::from(home));
+        }
+        self.qmk_home
+            .clone()
+            .or_else(|| home_dir().map(|h| h.join("qmk_firmware")))
+    }
+
+    /// External userspace whose keymaps take priority over the ones in qmk_firmware
+    pub fn userspace(&self) -> Option<PathBuf> {
+        if let Some(dir) = std::env::var_os("QMK_USERSPACE").filter(|d| !d.is_empty()) {
+            return Some(PathBuf::from(dir));
+        }
+        self.overlay_dir.clone()
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("qmk").join("qmk.ini"))
+}
+
+/// `$XDG_CONFIG_HOME`, or `~/.config`
+pub fn config_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => Some(home_dir()?.join(".config")),
+    }
+}
+
+pub fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .filter(|h| !h.is_empty())
+        .map(PathBuf::from)
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => Path::new(path).to_path_buf(),
+    }
+}
+
+/// Flattens `[section] key = value` into `section.key`, the way `qmk config` names them.
+/// configparser writes unset values as `None`, those are dropped.
+fn parse_ini(text: &str) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    let mut section = String::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_string();
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=').or_else(|| line.split_once(':')) else {
+            continue;
+        };
+        let value = value.trim();
+        if value.is_empty() || value == "None" {
+            continue;
+        }
+        values.insert(format!("{}.{}", section, key.trim()), value.to_string());
+    }
+    values
+}
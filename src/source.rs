@@ -0,0 +1,247 @@
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::process::{Child, Stdio};
+use std::sync::{Arc, Mutex};
+
+use crate::hidraw::{self, ConsoleReports};
+
+/// Somewhere console lines come from
+pub trait StateSource: Send {
+    /// Starts reading, which may block until there is something to read from, e.g. a client
+    /// connecting
+    fn open(&mut self) -> Result<Box<dyn BufRead + Send>, String>;
+
+    /// What the source reads from, for messages
+    fn describe(&self) -> String;
+
+    /// The process the source started, to be stopped when the visualiser exits
+    fn child_process(&self) -> Option<ChildProcess> {
+        None
+    }
+
+    /// Whether opening the source again after it ended can give more lines
+    fn reconnects(&self) -> bool {
+        true
+    }
+}
+
+/// Picks a source from a `--source` value:
+/// `qmk`, `hid`, `stdin` (or `-`), `file:PATH`, `tcp:ADDR` or `unix:PATH`
+pub fn parse(spec: &str) -> Result<Box<dyn StateSource>, String> {
+    let source: Box<dyn StateSource> = match spec.split_once(':') {
+        _ if spec == "qmk" => Box::new(QmkConsole::default()),
+        _ if spec == "hid" => Box::new(HidConsole { vid_pid: None }),
+        _ if spec == "stdin" || spec == "-" => Box::new(Stdin),
+        Some(("file", path)) => Box::new(FileSource { path: PathBuf::from(path) }),
+        Some(("tcp", addr)) => Box::new(TcpSource {
+            addr: addr.to_string(),
+            listener: None,
+        }),
+        Some(("unix", path)) => Box::new(UnixSource {
+            path: PathBuf::from(path),
+            listener: None,
+        }),
+        _ => {
+            return Err(format!(
+                "invalid source `{}`, expected qmk, hid, stdin, file:PATH, tcp:ADDR or unix:PATH",
+                spec
+            ));
+        }
+    };
+    Ok(source)
+}
+
+/// The `qmk console` child process, shared so it can be stopped from outside the reader
+#[derive(Debug, Clone, Default)]
+pub struct ChildProcess(Arc<Mutex<Option<Child>>>);
+
+impl ChildProcess {
+    pub fn kill(&self) {
+        if let Some(mut child) = self.0.lock().unwrap().take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Output of the qmk cli's `qmk console`
+#[derive(Debug, Default)]
+pub struct QmkConsole {
+    process: ChildProcess,
+}
+
+impl StateSource for QmkConsole {
+    fn open(&mut self) -> Result<Box<dyn BufRead + Send>, String> {
+        self.process.kill();
+        let mut child = std::process::Command::new("qmk")
+            .arg("console")
+            .stdout(Stdio::piped()) // capture stdout
+            .stderr(Stdio::piped()) // optional: capture stderr too
+            .spawn()
+            .map_err(|e| format!("failed to start `qmk console`: {}", e))?;
+        let stdout = child.stdout.take().expect("Failed to capture stdout");
+        // pass its complaints on, and keep the pipe from filling up
+        if let Some(stderr) = child.stderr.take() {
+            std::thread::spawn(move || {
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    eprintln!("qmk console: {}", line);
+                }
+            });
+        }
+        *self.process.0.lock().unwrap() = Some(child);
+        Ok(Box::new(BufReader::new(stdout)))
+    }
+
+    fn describe(&self) -> String {
+        "qmk console".to_string()
+    }
+
+    fn child_process(&self) -> Option<ChildProcess> {
+        Some(self.process.clone())
+    }
+}
+
+/// A keyboard's hidraw console, looked up again on every open so it survives replugging
+#[derive(Debug)]
+pub struct HidConsole {
+    pub vid_pid: Option<(u16, u16)>,
+}
+
+impl StateSource for HidConsole {
+    fn open(&mut self) -> Result<Box<dyn BufRead + Send>, String> {
+        let device = hidraw::select_console(self.vid_pid)?;
+        eprintln!("reading the console of {}", device);
+        Ok(Box::new(BufReader::new(ConsoleReports::open(&device.path)?)))
+    }
+
+    fn describe(&self) -> String {
+        match self.vid_pid {
+            Some((vid, pid)) => format!("console of {:04x}:{:04x}", vid, pid),
+            None => "keyboard console".to_string(),
+        }
+    }
+}
+
+/// Lines piped into the visualiser, e.g. a recorded log
+#[derive(Debug)]
+pub struct Stdin;
+
+impl StateSource for Stdin {
+    fn open(&mut self) -> Result<Box<dyn BufRead + Send>, String> {
+        Ok(Box::new(BufReader::new(std::io::stdin())))
+    }
+
+    fn describe(&self) -> String {
+        "stdin".to_string()
+    }
+
+    fn reconnects(&self) -> bool {
+        false
+    }
+}
+
+/// A file, named pipe or hidraw node
+#[derive(Debug)]
+pub struct FileSource {
+    pub path: PathBuf,
+}
+
+impl StateSource for FileSource {
+    fn open(&mut self) -> Result<Box<dyn BufRead + Send>, String> {
+        Ok(Box::new(BufReader::new(ConsoleReports::open(&self.path)?)))
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    // a regular file would only be replayed from the start, a missing one may be an unplugged
+    // keyboard's hidraw node
+    fn reconnects(&self) -> bool {
+        use std::os::unix::fs::FileTypeExt;
+        std::fs::metadata(&self.path).map_or(true, |m| {
+            m.file_type().is_fifo() || m.file_type().is_char_device()
+        })
+    }
+}
+
+/// Listens on a TCP address and reads from one client at a time, e.g. fed by
+/// `qmk console | nc host port` on the machine the keyboard is plugged into
+#[derive(Debug)]
+pub struct TcpSource {
+    addr: String,
+    listener: Option<TcpListener>,
+}
+
+impl StateSource for TcpSource {
+    fn open(&mut self) -> Result<Box<dyn BufRead + Send>, String> {
+        if self.listener.is_none() {
+            let listener = TcpListener::bind(&self.addr)
+                .map_err(|e| format!("can't listen on {}: {}", self.addr, e))?;
+            self.listener = Some(listener);
+        }
+        let (stream, peer) = self
+            .listener
+            .as_ref()
+            .unwrap()
+            .accept()
+            .map_err(|e| format!("can't accept on {}: {}", self.addr, e))?;
+        eprintln!("reading the console from {}", peer);
+        Ok(Box::new(BufReader::new(stream)))
+    }
+
+    fn describe(&self) -> String {
+        format!("tcp {}", self.addr)
+    }
+}
+
+/// Like `TcpSource`, on a Unix domain socket
+#[derive(Debug)]
+pub struct UnixSource {
+    path: PathBuf,
+    listener: Option<UnixListener>,
+}
+
+impl StateSource for UnixSource {
+    fn open(&mut self) -> Result<Box<dyn BufRead + Send>, String> {
+        if self.listener.is_none() {
+            // a socket left behind by an earlier run would make bind fail
+            if std::fs::symlink_metadata(&self.path).is_ok_and(|m| {
+                use std::os::unix::fs::FileTypeExt;
+                m.file_type().is_socket()
+            }) {
+                let _ = std::fs::remove_file(&self.path);
+            }
+            let listener = UnixListener::bind(&self.path)
+                .map_err(|e| format!("can't listen on {}: {}", self.path.display(), e))?;
+            self.listener = Some(listener);
+        }
+        let (stream, _) = self
+            .listener
+            .as_ref()
+            .unwrap()
+            .accept()
+            .map_err(|e| format!("can't accept on {}: {}", self.path.display(), e))?;
+        Ok(Box::new(BufReader::new(stream)))
+    }
+
+    fn describe(&self) -> String {
+        format!("unix socket {}", self.path.display())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_reconnects() {
+        // the test binary is as regular a file as a recorded log
+        let regular = std::env::current_exe().unwrap();
+        assert!(!FileSource { path: regular }.reconnects());
+        assert!(FileSource { path: "/dev/null".into() }.reconnects());
+        assert!(!parse("-").unwrap().reconnects());
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+
+use ab_glyph::{Font, FontArc, GlyphId, PxScale, ScaleFont, point};
+use tiny_skia::{Color, Pixmap, PixmapPaint, Rect, Transform};
+
+/// Space kept free between a legend and the edge of its area, relative to the legend's size
+const PADDING: f32 = 0.07;
+
+/// Legends shrink down to this fraction of their scale on one line before wrapping is tried
+const WRAP_BELOW: f32 = 0.75;
+
+/// Glyphs are positioned in steps of 1/SUBPIXEL_STEPS of a pixel
+const SUBPIXEL_STEPS: f32 = 4.0;
+
+/// Cached glyphs are dropped wholesale past this many, e.g. after lots of resizing
+const CACHE_LIMIT: usize = 4096;
+
+/// Rasterised glyphs of one font, keyed by everything that changes their pixels, so every
+/// glyph is only rasterised once and then composited in one go
+pub struct GlyphCache {
+    font: FontArc,
+    glyphs: HashMap<GlyphKey, Option<CachedGlyph>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct GlyphKey {
+    id: GlyphId,
+    scale: (u32, u32),
+    color: [u8; 4],
+    offset: (u8, u8), // sub-pixel position in SUBPIXEL_STEPS
+}
+
+struct CachedGlyph {
+    left: i32,
+    top: i32,
+    pixmap: Pixmap,
+}
+
+impl GlyphCache {
+    pub fn new(font: FontArc) -> Self {
+        GlyphCache {
+            font,
+            glyphs: HashMap::new(),
+        }
+    }
+
+    pub fn font(&self) -> &FontArc {
+        &self.font
+    }
+
+    /// Composites a glyph with its origin at `position`, rasterising it on first use
+    fn draw(&mut self, pixmap: &mut Pixmap, id: GlyphId, scale: PxScale, position: (f32, f32), color: Color) {
+        let (x, fx) = split_subpixel(position.0);
+        let (y, fy) = split_subpixel(position.1);
+        let color8 = color.to_color_u8();
+        let key = GlyphKey {
+            id,
+            scale: (scale.x.to_bits(), scale.y.to_bits()),
+            color: [color8.red(), color8.green(), color8.blue(), color8.alpha()],
+            offset: (fx, fy),
+        };
+        if self.glyphs.len() >= CACHE_LIMIT && !self.glyphs.contains_key(&key) {
+            self.glyphs.clear();
+        }
+        let font = &self.font;
+        let glyph = self
+            .glyphs
+            .entry(key)
+            .or_insert_with(|| rasterise(font, id, scale, (fx, fy), color));
+        if let Some(glyph) = glyph {
+            pixmap.draw_pixmap(
+                x + glyph.left,
+                y + glyph.top,
+                glyph.pixmap.as_ref(),
+                &PixmapPaint::default(),
+                Transform::identity(),
+                None,
+            );
+        }
+    }
+}
+
+// whole pixels and the remaining fraction in SUBPIXEL_STEPS
+fn split_subpixel(v: f32) -> (i32, u8) {
+    let steps = (v * SUBPIXEL_STEPS).round() as i32;
+    let whole = steps.div_euclid(SUBPIXEL_STEPS as i32);
+    (whole, steps.rem_euclid(SUBPIXEL_STEPS as i32) as u8)
+}
+
+/// Renders a glyph's coverage in `color` into a premultiplied pixmap of its own
+fn rasterise(font: &FontArc, id: GlyphId, scale: PxScale, offset: (u8, u8), color: Color) -> Option<CachedGlyph> {
+    let position = point(
+        offset.0 as f32 / SUBPIXEL_STEPS,
+        offset.1 as f32 / SUBPIXEL_STEPS,
+    );
+    let outline = font.outline_glyph(id.with_scale_and_position(scale, position))?;
+    let bounds = outline.px_bounds();
+    let mut pixmap = Pixmap::new(bounds.width() as u32, bounds.height() as u32)?;
+    let width = pixmap.width();
+    let pixels = pixmap.pixels_mut();
+    outline.draw(|x, y, coverage| {
+        let mut pixel = color;
+        pixel.apply_opacity(coverage);
+        pixels[(y * width + x) as usize] = pixel.premultiply().to_color_u8();
+    });
+    Some(CachedGlyph {
+        left: bounds.min.x as i32,
+        top: bounds.min.y as i32,
+        pixmap,
+    })
+}
+
+/// Draws one line of text with the top of its line box at `y`
+pub fn draw_text(
+    pixmap: &mut Pixmap,
+    text: &str,
+    glyphs: &mut GlyphCache,
+    scale: PxScale,
+    x: f32,
+    y: f32,
+    color: Color,
+) {
+    let baseline = y + glyphs.font().as_scaled(scale).ascent();
+    for (id, caret) in layout_line(text, glyphs.font(), scale) {
+        glyphs.draw(pixmap, id, scale, (x + caret, baseline), color);
+    }
+}
+
+/// Width of `text` on one line and the height of its line box
+pub fn measure_text(text: &str, font: &FontArc, scale: PxScale) -> (f32, f32) {
+    let scaled = font.as_scaled(scale);
+    let width = layout_line(text, font, scale)
+        .last()
+        .map_or(0.0, |&(id, caret)| caret + scaled.h_advance(id));
+    (width, scaled.height())
+}
+
+/// Draws `text` centred in `area`, shrunk from `scale` until it fits. Text that would have to
+/// shrink a lot on one line is wrapped over two lines instead, if that ends up larger.
+pub fn draw_text_fitted(
+    pixmap: &mut Pixmap,
+    text: &str,
+    glyphs: &mut GlyphCache,
+    scale: PxScale,
+    area: Rect,
+    color: Color,
+) {
+    let font = glyphs.font().clone();
+    let font = &font;
+    let one_line = vec![text.to_string()];
+    let (mut lines, mut factor) = (one_line.clone(), fit_factor(&one_line, font, scale, area));
+    if factor < WRAP_BELOW
+        && let Some(wrapped) = wrap_in_two(text)
+    {
+        let wrapped_factor = fit_factor(&wrapped, font, scale, area);
+        if wrapped_factor > factor {
+            (lines, factor) = (wrapped, wrapped_factor);
+        }
+    }
+
+    let scale = PxScale {
+        x: scale.x * factor,
+        y: scale.y * factor,
+    };
+    let line_height = font.as_scaled(scale).height();
+    let mut y = area.y() + (area.height() - line_height * lines.len() as f32) / 2.0;
+    for line in &lines {
+        let (width, _) = measure_text(line, font, scale);
+        let x = area.x() + (area.width() - width) / 2.0;
+        draw_text(pixmap, line, glyphs, scale, x, y, color);
+        y += line_height;
+    }
+}
+
+/// How much `lines` have to shrink to fit into `area`, never growing them
+fn fit_factor(lines: &[String], font: &FontArc, scale: PxScale, area: Rect) -> f32 {
+    let (width, line_height) = lines
+        .iter()
+        .map(|line| measure_text(line, font, scale))
+        .fold((0.0f32, 0.0f32), |(w, h), (lw, lh)| (w.max(lw), h.max(lh)));
+    let height = line_height * lines.len() as f32;
+    let available_width = (area.width() - 2.0 * PADDING * scale.x).max(1.0);
+    let available_height = (area.height() - 2.0 * PADDING * scale.y).max(1.0);
+    let mut factor = 1.0f32;
+    if width > available_width {
+        factor = factor.min(available_width / width);
+    }
+    if height > available_height {
+        factor = factor.min(available_height / height);
+    }
+    factor
+}
+
+/// Splits at the space closest to the middle, or in the middle of a single word
+fn wrap_in_two(text: &str) -> Option<Vec<String>> {
+    let chars = text.chars().collect::<Vec<_>>();
+    if chars.len() < 4 {
+        return None;
+    }
+    let middle = chars.len() / 2;
+    let split = chars
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.is_whitespace())
+        .map(|(i, _)| i)
+        .min_by_key(|&i| i.abs_diff(middle));
+    let (first, second) = match split {
+        Some(i) => (&chars[..i], &chars[i + 1..]),
+        None => chars.split_at(middle),
+    };
+    let first = first.iter().collect::<String>().trim().to_string();
+    let second = second.iter().collect::<String>().trim().to_string();
+    if first.is_empty() || second.is_empty() {
+        return None;
+    }
+    Some(vec![first, second])
+}
+
+/// Glyphs of a line with their horizontal offsets, kerning included
+fn layout_line(text: &str, font: &FontArc, scale: PxScale) -> Vec<(GlyphId, f32)> {
+    let scaled = font.as_scaled(scale);
+    let mut caret = 0.0;
+    let mut previous: Option<GlyphId> = None;
+    let mut glyphs = Vec::new();
+    for ch in text.chars() {
+        let id = scaled.glyph_id(ch);
+        if let Some(previous) = previous {
+            caret += scaled.kern(previous, id);
+        }
+        glyphs.push((id, caret));
+        caret += scaled.h_advance(id);
+        previous = Some(id);
+    }
+    glyphs
+}
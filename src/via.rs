@@ -0,0 +1,276 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use crate::hidraw;
+use crate::keymap_c_parser::{Constants, Keycode, Layer};
+
+/// VIA's reports are always this long, both ways
+pub const REPORT_SIZE: usize = 32;
+
+// commands from `quantum/via.h`
+const GET_PROTOCOL_VERSION: u8 = 0x01;
+const DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+/// What the firmware answers commands it doesn't know with
+const UNHANDLED: u8 = 0xFF;
+
+/// The first VIA protocol version with QMK's current keycode values
+const KEYCODES_PROTOCOL_VERSION: u16 = 12;
+
+/// How long the keyboard gets to answer a command
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Something VIA commands can be sent to
+pub trait Transport {
+    /// Sends a report and waits for the keyboard's answer to it
+    fn exchange(&mut self, request: &[u8; REPORT_SIZE]) -> Result<[u8; REPORT_SIZE], String>;
+}
+
+/// A keyboard's raw HID interface through its hidraw node
+pub struct HidrawTransport {
+    file: File,
+    reports: Receiver<[u8; REPORT_SIZE]>,
+}
+
+impl HidrawTransport {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = hidraw::open(path, true)?;
+        let mut reader = file
+            .try_clone()
+            .map_err(|e| format!("can't open {}: {}", path.display(), e))?;
+        // reads block, so they happen on their own thread and the answers can time out
+        let (sender, reports) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut report = [0; REPORT_SIZE];
+            while reader.read_exact(&mut report).is_ok() && sender.send(report).is_ok() {}
+        });
+        Ok(HidrawTransport { file, reports })
+    }
+}
+
+impl Transport for HidrawTransport {
+    fn exchange(&mut self, request: &[u8; REPORT_SIZE]) -> Result<[u8; REPORT_SIZE], String> {
+        // answers to earlier commands that timed out would be taken for this one's
+        while self.reports.try_recv().is_ok() {}
+        // hidraw wants the report id first, QMK's raw HID has none so it's 0
+        let mut report = [0; REPORT_SIZE + 1];
+        report[1..].copy_from_slice(request);
+        self.file
+            .write_all(&report)
+            .map_err(|e| format!("can't send to the keyboard: {}", e))?;
+        loop {
+            match self.reports.recv_timeout(TIMEOUT) {
+                // the firmware echoes the command in the first byte
+                Ok(answer) if answer[0] == request[0] || answer[0] == UNHANDLED => return Ok(answer),
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err("the keyboard didn't answer".to_string());
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err("the keyboard went away".to_string());
+                }
+            }
+        }
+    }
+}
+
+/// The VIA commands for reading a keyboard's dynamic keymap from its EEPROM
+pub struct Via<T> {
+    transport: T,
+}
+
+impl<T: Transport> Via<T> {
+    pub fn new(transport: T) -> Self {
+        Via { transport }
+    }
+
+    fn command(&mut self, command: u8, args: &[u8]) -> Result<[u8; REPORT_SIZE], String> {
+        let mut request = [0; REPORT_SIZE];
+        request[0] = command;
+        request[1..=args.len()].copy_from_slice(args);
+        let answer = self.transport.exchange(&request)?;
+        if answer[0] == UNHANDLED {
+            return Err(format!("the keyboard doesn't support VIA command {:#04x}", command));
+        }
+        Ok(answer)
+    }
+
+    pub fn protocol_version(&mut self) -> Result<u16, String> {
+        let answer = self.command(GET_PROTOCOL_VERSION, &[])?;
+        Ok(u16::from_be_bytes([answer[1], answer[2]]))
+    }
+
+    pub fn layer_count(&mut self) -> Result<u8, String> {
+        Ok(self.command(DYNAMIC_KEYMAP_GET_LAYER_COUNT, &[])?[1])
+    }
+
+    /// `len` bytes of the keymap buffer from `offset`, fetched a report at a time
+    pub fn keymap_buffer(&mut self, offset: usize, len: usize) -> Result<Vec<u8>, String> {
+        // command, offset and size come before the data
+        const CHUNK: usize = REPORT_SIZE - 4;
+        let mut buffer = Vec::with_capacity(len);
+        while buffer.len() < len {
+            let position = offset + buffer.len();
+            let size = (len - buffer.len()).min(CHUNK);
+            let position = u16::try_from(position)
+                .map_err(|_| format!("keymap offset {} is out of VIA's range", position))?;
+            let [high, low] = position.to_be_bytes();
+            let answer = self.command(DYNAMIC_KEYMAP_GET_BUFFER, &[high, low, size as u8])?;
+            buffer.extend_from_slice(&answer[4..4 + size]);
+        }
+        Ok(buffer)
+    }
+
+    /// Every layer of the dynamic keymap, in the order of the layout keys at `matrix`. The
+    /// buffer holds each layer's keycodes row by row, big-endian.
+    pub fn read_keymap(
+        &mut self,
+        rows: usize,
+        cols: usize,
+        matrix: &[Option<(u8, u8)>],
+    ) -> Result<Vec<Layer>, String> {
+        let version = self.protocol_version()?;
+        if version < KEYCODES_PROTOCOL_VERSION {
+            eprintln!(
+                "warning: the keyboard speaks VIA protocol {}, its keycodes may be shown wrong",
+                version
+            );
+        }
+        let layer_count = self.layer_count()? as usize;
+        if layer_count == 0 {
+            return Err("the keyboard has no dynamic keymap layers".to_string());
+        }
+        let buffer = self.keymap_buffer(0, layer_count * rows * cols * 2)?;
+
+        let constants = Constants::default();
+        let layers = (0..layer_count)
+            .map(|layer| Layer {
+                name: layer.to_string(),
+                keys: matrix
+                    .iter()
+                    .map(|position| match position {
+                        Some((row, col)) if (*row as usize) < rows && (*col as usize) < cols => {
+                            let at = ((layer * rows + *row as usize) * cols + *col as usize) * 2;
+                            Keycode::decode(u16::from_be_bytes([buffer[at], buffer[at + 1]]), &constants)
+                        }
+                        _ => Keycode::NONE,
+                    })
+                    .collect(),
+            })
+            .collect();
+        Ok(layers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers VIA commands from a keymap buffer held in memory
+    struct FakeDevice {
+        protocol_version: u16,
+        layer_count: u8,
+        buffer: Vec<u8>,
+        /// Commands answered with `id_unhandled`
+        unhandled: Vec<u8>,
+        requests: Vec<[u8; REPORT_SIZE]>,
+    }
+
+    impl FakeDevice {
+        /// `layers` of `rows` × `cols` keycodes, each key's being `layer << 8 | row << 4 | col`
+        fn new(layers: u8, rows: usize, cols: usize) -> Self {
+            let buffer = (0..layers as u16)
+                .flat_map(|layer| {
+                    (0..rows as u16)
+                        .flat_map(move |row| (0..cols as u16).map(move |col| layer << 8 | row << 4 | col))
+                })
+                .flat_map(u16::to_be_bytes)
+                .collect();
+            FakeDevice {
+                protocol_version: KEYCODES_PROTOCOL_VERSION,
+                layer_count: layers,
+                buffer,
+                unhandled: Vec::new(),
+                requests: Vec::new(),
+            }
+        }
+    }
+
+    impl Transport for FakeDevice {
+        fn exchange(&mut self, request: &[u8; REPORT_SIZE]) -> Result<[u8; REPORT_SIZE], String> {
+            self.requests.push(*request);
+            let mut answer = *request;
+            if self.unhandled.contains(&request[0]) {
+                answer[0] = UNHANDLED;
+                return Ok(answer);
+            }
+            match request[0] {
+                GET_PROTOCOL_VERSION => answer[1..3].copy_from_slice(&self.protocol_version.to_be_bytes()),
+                DYNAMIC_KEYMAP_GET_LAYER_COUNT => answer[1] = self.layer_count,
+                DYNAMIC_KEYMAP_GET_BUFFER => {
+                    let offset = u16::from_be_bytes([request[1], request[2]]) as usize;
+                    let size = request[3] as usize;
+                    assert!(size <= REPORT_SIZE - 4, "asked for {} bytes", size);
+                    answer[4..4 + size].copy_from_slice(&self.buffer[offset..offset + size]);
+                }
+                _ => answer[0] = UNHANDLED,
+            }
+            Ok(answer)
+        }
+    }
+
+    #[test]
+    fn read_keymap() {
+        // 20 bytes a layer, so the 28-byte chunks straddle layer boundaries
+        let (rows, cols) = (2, 5);
+        let mut via = Via::new(FakeDevice::new(3, rows, cols));
+        let matrix = [Some((0, 0)), Some((1, 4)), None, Some((0, 3)), Some((2, 0))];
+        let layers = via.read_keymap(rows, cols, &matrix).unwrap();
+
+        let constants = Constants::default();
+        let key = |code: u16| Keycode::decode(code, &constants).to_string();
+        assert_eq!(layers.len(), 3);
+        for (index, layer) in layers.iter().enumerate() {
+            let base = (index as u16) << 8;
+            assert_eq!(layer.name, index.to_string());
+            let keys = layer.keys.iter().map(Keycode::to_string).collect::<Vec<_>>();
+            assert_eq!(
+                keys,
+                [
+                    key(base),
+                    key(base | 0x14),
+                    key(0),
+                    key(base | 0x03),
+                    // outside the matrix
+                    key(0),
+                ]
+            );
+        }
+
+        let chunks = via
+            .transport
+            .requests
+            .iter()
+            .filter(|request| request[0] == DYNAMIC_KEYMAP_GET_BUFFER)
+            .map(|request| (u16::from_be_bytes([request[1], request[2]]), request[3]))
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, [(0, 28), (28, 28), (56, 4)]);
+    }
+
+    #[test]
+    fn unhandled() {
+        let mut device = FakeDevice::new(1, 1, 1);
+        device.unhandled.push(DYNAMIC_KEYMAP_GET_LAYER_COUNT);
+        let error = Via::new(device).read_keymap(1, 1, &[Some((0, 0))]).unwrap_err();
+        assert!(error.contains("0x11"), "{}", error);
+    }
+
+    #[test]
+    fn no_layers() {
+        let mut via = Via::new(FakeDevice::new(0, 1, 1));
+        assert!(via.read_keymap(1, 1, &[Some((0, 0))]).is_err());
+    }
+}